@@ -0,0 +1,1048 @@
+use std::f64::consts::PI;
+use std::fmt;
+use std::io::prelude::*;
+use std::str::FromStr;
+
+use crate::{EGSError, EGSResult, PHSPReader, Particle, Record};
+
+/// Which particles an analysis counts, decided by the charge bits of the latch.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Selection {
+    All,
+    Photons,
+    Electrons,
+    Positrons,
+    Charged,
+}
+
+impl Selection {
+    pub fn matches(&self, record: &Record) -> bool {
+        match *self {
+            Selection::All => true,
+            Selection::Photons => record.particle() == Particle::Photon,
+            Selection::Electrons => record.particle() == Particle::Electron,
+            Selection::Positrons => record.particle() == Particle::Positron,
+            Selection::Charged => record.charged(),
+        }
+    }
+}
+
+impl fmt::Display for Selection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match *self {
+            Selection::All => "all",
+            Selection::Photons => "photons",
+            Selection::Electrons => "electrons",
+            Selection::Positrons => "positrons",
+            Selection::Charged => "charged",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl FromStr for Selection {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Selection, String> {
+        match s {
+            "all" => Ok(Selection::All),
+            "photons" | "photon" => Ok(Selection::Photons),
+            "electrons" | "electron" => Ok(Selection::Electrons),
+            "positrons" | "positron" => Ok(Selection::Positrons),
+            "charged" => Ok(Selection::Charged),
+            _ => Err(format!("unknown particle type {:?}", s)),
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ZoneShape {
+    /// Rings bounded by circles about the z axis.
+    Annular,
+    /// Square rings bounded by squares about the z axis, binned on max(|x|, |y|).
+    Square,
+}
+
+/// BEAMDP style scoring zones: `bins` equal width rings out to `max_radius` (the half
+/// width for square zones).
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Zones {
+    pub shape: ZoneShape,
+    pub bins: usize,
+    pub max_radius: f64,
+}
+
+impl Zones {
+    pub fn new(shape: ZoneShape, bins: usize, max_radius: f64) -> EGSResult<Zones> {
+        if bins == 0 {
+            return Err(EGSError::BadBinning("no zones".to_string()));
+        }
+        if !max_radius.is_finite() || max_radius <= 0.0 {
+            return Err(EGSError::BadBinning(format!(
+                "maximum radius {} is not positive",
+                max_radius
+            )));
+        }
+        Ok(Zones {
+            shape,
+            bins,
+            max_radius,
+        })
+    }
+
+    /// The zone holding `(x, y)`, if any. A NaN position is in no zone.
+    pub fn zone(&self, x: f32, y: f32) -> Option<usize> {
+        let (x, y) = (x as f64, y as f64);
+        let distance = match self.shape {
+            ZoneShape::Annular => (x * x + y * y).sqrt(),
+            ZoneShape::Square => x.abs().max(y.abs()),
+        };
+        // max ignores a NaN, so the coordinates are checked rather than the distance
+        if x.is_nan() || y.is_nan() || distance >= self.max_radius {
+            return None;
+        }
+        Some(((distance / self.max_radius * self.bins as f64) as usize).min(self.bins - 1))
+    }
+
+    pub fn edges(&self) -> Vec<f64> {
+        (0..=self.bins)
+            .map(|i| self.max_radius * i as f64 / self.bins as f64)
+            .collect()
+    }
+
+    /// Area of the zone in cm².
+    pub fn area(&self, zone: usize) -> f64 {
+        let edges = self.edges();
+        let (inner, outer) = (edges[zone], edges[zone + 1]);
+        match self.shape {
+            ZoneShape::Annular => PI * (outer * outer - inner * inner),
+            ZoneShape::Square => 4.0 * (outer * outer - inner * inner),
+        }
+    }
+}
+
+/// A region of the scoring plane. The area is unknown for `Everywhere`.
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+pub enum Field {
+    #[default]
+    Everywhere,
+    /// A circle about the z axis.
+    Circle { radius: f32 },
+    Rectangle {
+        x_min: f32,
+        x_max: f32,
+        y_min: f32,
+        y_max: f32,
+    },
+}
+
+impl Field {
+    pub fn contains(&self, x: f32, y: f32) -> bool {
+        match *self {
+            Field::Everywhere => true,
+            Field::Circle { radius } => x * x + y * y < radius * radius,
+            Field::Rectangle {
+                x_min,
+                x_max,
+                y_min,
+                y_max,
+            } => x >= x_min && x < x_max && y >= y_min && y < y_max,
+        }
+    }
+
+    /// Area in cm².
+    pub fn area(&self) -> Option<f64> {
+        match *self {
+            Field::Everywhere => None,
+            Field::Circle { radius } => Some(PI * radius as f64 * radius as f64),
+            Field::Rectangle {
+                x_min,
+                x_max,
+                y_min,
+                y_max,
+            } => Some((x_max - x_min) as f64 * (y_max - y_min) as f64),
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Scale {
+    Linear,
+    Log,
+}
+
+/// `bins` bins from `min` to `max`, of equal width or equal width in log.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Binning {
+    pub min: f64,
+    pub max: f64,
+    pub bins: usize,
+    pub scale: Scale,
+}
+
+impl Binning {
+    pub fn new(min: f64, max: f64, bins: usize, scale: Scale) -> EGSResult<Binning> {
+        if bins == 0 {
+            return Err(EGSError::BadBinning("no bins".to_string()));
+        }
+        if min.is_nan() || max.is_nan() || min >= max {
+            return Err(EGSError::BadBinning(format!(
+                "minimum {} is not below maximum {}",
+                min, max
+            )));
+        }
+        if scale == Scale::Log && min <= 0.0 {
+            return Err(EGSError::BadBinning(format!(
+                "log bins need a positive minimum, not {}",
+                min
+            )));
+        }
+        Ok(Binning {
+            min,
+            max,
+            bins,
+            scale,
+        })
+    }
+
+    fn position(&self, value: f64) -> f64 {
+        match self.scale {
+            Scale::Linear => (value - self.min) / (self.max - self.min),
+            Scale::Log => (value / self.min).ln() / (self.max / self.min).ln(),
+        }
+    }
+
+    /// The bin holding `value`. The maximum itself falls in the last bin, so the most
+    /// energetic particle is counted when binning from the header energies. A NaN is in
+    /// no bin.
+    pub fn bin(&self, value: f64) -> Option<usize> {
+        if !value.is_finite() || value < self.min || value > self.max {
+            return None;
+        }
+        Some(((self.position(value) * self.bins as f64) as usize).min(self.bins - 1))
+    }
+
+    pub fn edges(&self) -> Vec<f64> {
+        (0..=self.bins)
+            .map(|i| {
+                let fraction = i as f64 / self.bins as f64;
+                match self.scale {
+                    Scale::Linear => self.min + (self.max - self.min) * fraction,
+                    Scale::Log => self.min * (self.max / self.min).powf(fraction),
+                }
+            })
+            .collect()
+    }
+}
+
+/// Weighted sums for one bin. `add(weight, value)` scores a particle of the given
+/// weight carrying a value (energy for energy fluence, for example).
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+pub struct Tally {
+    pub count: u64,
+    /// Sum of w.
+    pub weight: f64,
+    /// Sum of w².
+    pub weight_squared: f64,
+    /// Sum of w·v.
+    pub value: f64,
+    /// Sum of (w·v)².
+    pub value_squared: f64,
+    /// Sum of w·v².
+    pub moment: f64,
+}
+
+impl Tally {
+    pub fn add(&mut self, weight: f64, value: f64) {
+        self.count += 1;
+        self.weight += weight;
+        self.weight_squared += weight * weight;
+        self.value += weight * value;
+        self.value_squared += (weight * value) * (weight * value);
+        self.moment += weight * value * value;
+    }
+
+    /// The result and its uncertainty. Totals are divided by `scale` (area, bin width
+    /// and histories, say) while counts and means are not scaled. A mean of no particles
+    /// is NaN.
+    pub fn result(&self, quantity: Quantity, scale: f64) -> (f64, f64) {
+        match quantity {
+            Quantity::Count => (self.count as f64, (self.count as f64).sqrt()),
+            Quantity::Fluence | Quantity::EnergyFluence => {
+                (self.value / scale, self.value_squared.sqrt() / scale)
+            }
+            Quantity::MeanEnergy => {
+                let mean = self.value / self.weight;
+                // standard error of a weighted mean, with (sum w)²/sum w² effective particles
+                let variance = (self.moment / self.weight - mean * mean).max(0.0);
+                (mean, (variance * self.weight_squared).sqrt() / self.weight)
+            }
+        }
+    }
+}
+
+/// One column of a distribution, for example the photon fluence in each zone.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Series {
+    pub label: String,
+    pub values: Vec<f64>,
+    /// One standard deviation, treating particles as independent.
+    pub uncertainties: Vec<f64>,
+}
+
+/// A binned result: every series shares the bin `edges`, so there is one more edge than
+/// there are values in each series. `log_x` marks bins of equal width in log.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Distribution {
+    pub title: String,
+    pub x_label: String,
+    pub y_label: String,
+    pub edges: Vec<f64>,
+    pub log_x: bool,
+    pub series: Vec<Series>,
+}
+
+impl Distribution {
+    pub fn bins(&self) -> usize {
+        self.edges.len().saturating_sub(1)
+    }
+
+    /// The middle of each bin, geometric for log bins.
+    pub fn centers(&self) -> Vec<f64> {
+        self.edges
+            .windows(2)
+            .map(|e| {
+                if self.log_x {
+                    (e[0] * e[1]).sqrt()
+                } else {
+                    (e[0] + e[1]) / 2.0
+                }
+            })
+            .collect()
+    }
+
+    /// Combines the results of the same analysis of several files into one distribution,
+    /// prefixing each series label with the name of its file. A single distribution is
+    /// returned as it is.
+    pub fn merge(mut labelled: Vec<(String, Distribution)>) -> EGSResult<Distribution> {
+        if labelled.len() == 1 {
+            return Ok(labelled.remove(0).1);
+        }
+        let mut merged = match labelled.first() {
+            Some((_, first)) => Distribution {
+                series: Vec::new(),
+                ..first.clone()
+            },
+            None => return Err(EGSError::BadBinning("nothing to merge".to_string())),
+        };
+        for (name, distribution) in labelled {
+            if distribution.edges != merged.edges {
+                return Err(EGSError::BadBinning(format!(
+                    "{} is binned differently from the other files",
+                    name
+                )));
+            }
+            for mut series in distribution.series {
+                series.label = format!("{}: {}", name, series.label);
+                merged.series.push(series);
+            }
+        }
+        Ok(merged)
+    }
+}
+
+// results are per incident particle, unless the header has no count of them
+pub(crate) fn histories(total_particles_in_source: f32) -> f64 {
+    if total_particles_in_source > 0.0 {
+        total_particles_in_source as f64
+    } else {
+        1.0
+    }
+}
+
+fn series(selection: &Selection, results: &[(f64, f64)]) -> Series {
+    Series {
+        label: selection.to_string(),
+        values: results.iter().map(|r| r.0).collect(),
+        uncertainties: results.iter().map(|r| r.1).collect(),
+    }
+}
+
+fn zone_label(zones: &Zones) -> String {
+    match zones.shape {
+        ZoneShape::Annular => "radius (cm)".to_string(),
+        ZoneShape::Square => "half width (cm)".to_string(),
+    }
+}
+
+/// What a particle contributes to its bin.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Quantity {
+    /// Weight.
+    Fluence,
+    /// Weight times energy, kinetic for charged particles.
+    EnergyFluence,
+    /// Weighted mean kinetic energy.
+    MeanEnergy,
+    /// Number of particles, ignoring weight.
+    Count,
+}
+
+impl Quantity {
+    fn value(&self, record: &Record) -> f64 {
+        match *self {
+            Quantity::Fluence | Quantity::Count => 1.0,
+            Quantity::EnergyFluence | Quantity::MeanEnergy => record.kinetic_energy() as f64,
+        }
+    }
+}
+
+/// Fluence or energy fluence per unit area in each zone, per incident particle, or the
+/// mean energy in each zone, with one series for each selection.
+pub fn profile<R: Read>(
+    reader: PHSPReader<R>,
+    zones: &Zones,
+    selections: &[Selection],
+    quantity: Quantity,
+) -> EGSResult<Distribution> {
+    let histories = histories(reader.header.total_particles_in_source);
+    let mut tallies = vec![vec![Tally::default(); zones.bins]; selections.len()];
+    for record in reader {
+        let record = record?;
+        let zone = match zones.zone(record.x_cm, record.y_cm) {
+            Some(zone) => zone,
+            None => continue,
+        };
+        for (selection, tallies) in selections.iter().zip(tallies.iter_mut()) {
+            if selection.matches(&record) {
+                tallies[zone].add(record.get_weight() as f64, quantity.value(&record));
+            }
+        }
+    }
+    let series = selections
+        .iter()
+        .zip(tallies.iter())
+        .map(|(selection, tallies)| {
+            let results: Vec<(f64, f64)> = (0..zones.bins)
+                .map(|zone| tallies[zone].result(quantity, zones.area(zone) * histories))
+                .collect();
+            series(selection, &results)
+        })
+        .collect();
+    let (title, y_label) = match quantity {
+        Quantity::Fluence => (
+            "Fluence vs position",
+            "fluence (cm^-2 per incident particle)",
+        ),
+        Quantity::EnergyFluence => (
+            "Energy fluence vs position",
+            "energy fluence (MeV cm^-2 per incident particle)",
+        ),
+        Quantity::MeanEnergy => ("Mean energy vs position", "mean kinetic energy (MeV)"),
+        Quantity::Count => ("Particles vs position", "particles"),
+    };
+    Ok(Distribution {
+        title: title.to_string(),
+        x_label: zone_label(zones),
+        y_label: y_label.to_string(),
+        edges: zones.edges(),
+        log_x: false,
+        series,
+    })
+}
+
+// tallies for each selection of the particles in the field, binned on `coordinate`
+fn histogram<R: Read, F: Fn(&Record) -> Option<f64>>(
+    reader: PHSPReader<R>,
+    binning: &Binning,
+    field: &Field,
+    selections: &[Selection],
+    quantity: Quantity,
+    coordinate: F,
+) -> EGSResult<(Vec<Vec<Tally>>, f64)> {
+    let histories = histories(reader.header.total_particles_in_source);
+    let mut tallies = vec![vec![Tally::default(); binning.bins]; selections.len()];
+    for record in reader {
+        let record = record?;
+        if !field.contains(record.x_cm, record.y_cm) {
+            continue;
+        }
+        let bin = match coordinate(&record).and_then(|c| binning.bin(c)) {
+            Some(bin) => bin,
+            None => continue,
+        };
+        for (selection, tallies) in selections.iter().zip(tallies.iter_mut()) {
+            if selection.matches(&record) {
+                tallies[bin].add(record.get_weight() as f64, quantity.value(&record));
+            }
+        }
+    }
+    Ok((tallies, histories))
+}
+
+/// The smallest positive kinetic energy of any particle, if there is one. The header
+/// minimum only covers charged particles, so it can't bound a photon spectrum.
+pub fn lowest_energy<R: Read>(reader: PHSPReader<R>) -> EGSResult<Option<f64>> {
+    let mut lowest: Option<f64> = None;
+    for record in reader {
+        let energy = record?.kinetic_energy() as f64;
+        if energy > 0.0 && lowest.is_none_or(|lowest| energy < lowest) {
+            lowest = Some(energy);
+        }
+    }
+    Ok(lowest)
+}
+
+/// Fluence or energy fluence per MeV in each energy bin, over the particles inside
+/// `field`, per unit area and per incident particle. Without a field area the result is
+/// per MeV per incident particle, summed over the whole plane.
+pub fn spectrum<R: Read>(
+    reader: PHSPReader<R>,
+    binning: &Binning,
+    field: &Field,
+    selections: &[Selection],
+    quantity: Quantity,
+) -> EGSResult<Distribution> {
+    let (tallies, histories) = histogram(reader, binning, field, selections, quantity, |r| {
+        Some(r.kinetic_energy() as f64)
+    })?;
+    let edges = binning.edges();
+    let area = field.area().unwrap_or(1.0);
+    let series = selections
+        .iter()
+        .zip(tallies.iter())
+        .map(|(selection, tallies)| {
+            let results: Vec<(f64, f64)> = (0..binning.bins)
+                .map(|bin| {
+                    let scale = (edges[bin + 1] - edges[bin]) * area * histories;
+                    tallies[bin].result(quantity, scale)
+                })
+                .collect();
+            series(selection, &results)
+        })
+        .collect();
+    let per_area = if field.area().is_some() { " cm^-2" } else { "" };
+    let (title, y_label) = match quantity {
+        Quantity::Fluence => (
+            "Spectral distribution",
+            format!("fluence (MeV^-1{} per incident particle)", per_area),
+        ),
+        Quantity::EnergyFluence => (
+            "Energy fluence distribution",
+            format!("energy fluence (MeV MeV^-1{} per incident particle)", per_area),
+        ),
+        Quantity::MeanEnergy => (
+            "Mean energy distribution",
+            "mean kinetic energy (MeV)".to_string(),
+        ),
+        Quantity::Count => ("Particles per energy bin", "particles".to_string()),
+    };
+    Ok(Distribution {
+        title: title.to_string(),
+        x_label: "kinetic energy (MeV)".to_string(),
+        y_label,
+        edges,
+        log_x: binning.scale == Scale::Log,
+        series,
+    })
+}
+
+/// Angle in degrees between the direction of motion and the z axis, above 90 for
+/// particles moving backwards.
+pub fn polar_angle(record: &Record) -> f64 {
+    let z_cos = (record.z_cos() as f64).min(1.0);
+    let z_cos = if record.z_positive() { z_cos } else { -z_cos };
+    z_cos.acos().to_degrees()
+}
+
+/// Fluence or energy fluence per steradian in bins of polar angle (in degrees), over the
+/// particles inside `field`, per incident particle.
+pub fn angular<R: Read>(
+    reader: PHSPReader<R>,
+    binning: &Binning,
+    field: &Field,
+    selections: &[Selection],
+    quantity: Quantity,
+) -> EGSResult<Distribution> {
+    let (tallies, histories) = histogram(reader, binning, field, selections, quantity, |r| {
+        Some(polar_angle(r))
+    })?;
+    let edges = binning.edges();
+    let series = selections
+        .iter()
+        .zip(tallies.iter())
+        .map(|(selection, tallies)| {
+            let results: Vec<(f64, f64)> = (0..binning.bins)
+                .map(|bin| {
+                    let (low, high) = (edges[bin].to_radians(), edges[bin + 1].to_radians());
+                    let solid_angle = 2.0 * PI * (low.cos() - high.cos());
+                    tallies[bin].result(quantity, solid_angle * histories)
+                })
+                .collect();
+            series(selection, &results)
+        })
+        .collect();
+    let y_label = match quantity {
+        Quantity::Fluence => "particles (sr^-1 per incident particle)",
+        Quantity::EnergyFluence => "energy (MeV sr^-1 per incident particle)",
+        Quantity::MeanEnergy => "mean kinetic energy (MeV)",
+        Quantity::Count => "particles",
+    };
+    Ok(Distribution {
+        title: "Angular distribution".to_string(),
+        x_label: "polar angle (degrees)".to_string(),
+        y_label: y_label.to_string(),
+        edges,
+        log_x: binning.scale == Scale::Log,
+        series,
+    })
+}
+
+/// Fluence or energy fluence per cm of ZLAST, the z of each particle's last interaction,
+/// over the particles inside `field` (per unit area if it has one), per incident
+/// particle. Only MODE2 files have ZLAST, and unknown (NaN) values are skipped.
+pub fn zlast<R: Read>(
+    reader: PHSPReader<R>,
+    binning: &Binning,
+    field: &Field,
+    selections: &[Selection],
+    quantity: Quantity,
+) -> EGSResult<Distribution> {
+    if !reader.header.using_zlast {
+        return Err(EGSError::MissingZlast);
+    }
+    let (tallies, histories) = histogram(reader, binning, field, selections, quantity, |r| {
+        r.zlast.map(|z| z as f64).filter(|z| !z.is_nan())
+    })?;
+    let edges = binning.edges();
+    let area = field.area().unwrap_or(1.0);
+    let series = selections
+        .iter()
+        .zip(tallies.iter())
+        .map(|(selection, tallies)| {
+            let results: Vec<(f64, f64)> = (0..binning.bins)
+                .map(|bin| {
+                    let scale = (edges[bin + 1] - edges[bin]) * area * histories;
+                    tallies[bin].result(quantity, scale)
+                })
+                .collect();
+            series(selection, &results)
+        })
+        .collect();
+    let per_area = if field.area().is_some() { " cm^-2" } else { "" };
+    let y_label = match quantity {
+        Quantity::Fluence => format!("fluence (cm^-1{} per incident particle)", per_area),
+        Quantity::EnergyFluence => format!(
+            "energy fluence (MeV cm^-1{} per incident particle)",
+            per_area
+        ),
+        Quantity::MeanEnergy => "mean kinetic energy (MeV)".to_string(),
+        Quantity::Count => "particles".to_string(),
+    };
+    Ok(Distribution {
+        title: "ZLAST distribution".to_string(),
+        x_label: "z of last interaction (cm)".to_string(),
+        y_label,
+        edges,
+        log_x: binning.scale == Scale::Log,
+        series,
+    })
+}
+
+/// How the weight of one selection of particles is spread.
+#[derive(Debug, Clone, PartialEq)]
+pub struct WeightSummary {
+    pub label: String,
+    pub count: u64,
+    pub total_weight: f64,
+    pub min_weight: f64,
+    pub max_weight: f64,
+    /// Fraction of the particles heavier than the fat particle threshold.
+    pub fat_fraction: f64,
+    /// Fraction of the total weight those particles carry.
+    pub fat_weight_fraction: f64,
+    /// Kish effective sample size, (sum w)² / sum w².
+    pub effective_particles: f64,
+    /// The heaviest particle's share of the total weight.
+    pub max_weight_fraction: f64,
+}
+
+/// Number of particles in `bins` log spaced bins of |weight|, between the smallest and
+/// largest positive weights selected, and a summary for each selection. Particles heavier
+/// than `fat_weight` (by default twice the smallest weight of their selection) count as
+/// fat. This reads the file twice, once to find the range of weights. Summaries of an
+/// empty selection are NaN.
+pub fn weight_distribution<R: Read + Seek>(
+    mut reader: PHSPReader<R>,
+    bins: usize,
+    selections: &[Selection],
+    fat_weight: Option<f64>,
+) -> EGSResult<(Distribution, Vec<WeightSummary>)> {
+    let mut min_weights = vec![f64::INFINITY; selections.len()];
+    let mut max_weight = 0.0_f64;
+    for record in reader.by_ref() {
+        let record = record?;
+        let weight = record.get_weight() as f64;
+        for (i, selection) in selections.iter().enumerate() {
+            if weight > 0.0 && selection.matches(&record) {
+                min_weights[i] = min_weights[i].min(weight);
+                max_weight = max_weight.max(weight);
+            }
+        }
+    }
+    if max_weight == 0.0 {
+        return Err(EGSError::BadBinning(
+            "no selected particle has a positive weight".to_string(),
+        ));
+    }
+    let min_weight = min_weights.iter().copied().fold(f64::INFINITY, f64::min);
+    // a single weight still needs a bin around it
+    let (low, high) = if min_weight < max_weight {
+        (min_weight, max_weight)
+    } else {
+        (min_weight / 2.0, max_weight * 2.0)
+    };
+    let binning = Binning::new(low, high, bins, Scale::Log)?;
+
+    reader.seek_record(0)?;
+    let mut tallies = vec![vec![Tally::default(); bins]; selections.len()];
+    let mut weights = vec![(Tally::default(), Tally::default(), 0.0_f64); selections.len()];
+    for record in reader {
+        let record = record?;
+        let weight = record.get_weight() as f64;
+        for (i, selection) in selections.iter().enumerate() {
+            if !selection.matches(&record) {
+                continue;
+            }
+            // all particles, the fat ones, and the heaviest weight
+            let (all, fat, max) = &mut weights[i];
+            all.add(weight, 1.0);
+            if weight > fat_weight.unwrap_or(2.0 * min_weights[i]) {
+                fat.add(weight, 1.0);
+            }
+            *max = max.max(weight);
+            if let Some(bin) = binning.bin(weight) {
+                tallies[i][bin].add(weight, 1.0);
+            }
+        }
+    }
+    let summaries = selections
+        .iter()
+        .zip(weights.iter().zip(min_weights.iter()))
+        .map(|(selection, ((all, fat, max), min))| WeightSummary {
+            label: selection.to_string(),
+            count: all.count,
+            total_weight: all.weight,
+            min_weight: if min.is_finite() { *min } else { f64::NAN },
+            max_weight: if all.count > 0 { *max } else { f64::NAN },
+            fat_fraction: fat.count as f64 / all.count as f64,
+            fat_weight_fraction: fat.weight / all.weight,
+            effective_particles: all.weight * all.weight / all.weight_squared,
+            max_weight_fraction: max / all.weight,
+        })
+        .collect();
+    let series = selections
+        .iter()
+        .zip(tallies.iter())
+        .map(|(selection, tallies)| {
+            let results: Vec<(f64, f64)> = tallies
+                .iter()
+                .map(|t| t.result(Quantity::Count, 1.0))
+                .collect();
+            series(selection, &results)
+        })
+        .collect();
+    let distribution = Distribution {
+        title: "Weight distribution".to_string(),
+        x_label: "|weight|".to_string(),
+        y_label: "particles".to_string(),
+        edges: binning.edges(),
+        log_x: true,
+        series,
+    };
+    Ok((distribution, summaries))
+}
+
+/// Writes a distribution as whitespace separated columns: the bin edges, then a value
+/// and an uncertainty for each series. The title and labels go in `#` comments.
+pub fn write_table<W: Write>(distribution: &Distribution, mut output: W) -> EGSResult<()> {
+    writeln!(output, "# {}", distribution.title)?;
+    writeln!(
+        output,
+        "# x: {}, y: {}",
+        distribution.x_label, distribution.y_label
+    )?;
+    write!(output, "# {:>12} {:>14}", "low", "high")?;
+    for series in distribution.series.iter() {
+        // one word per column, even for labels like "first.egsphsp1: photons"
+        let label = series.label.split_whitespace().collect::<Vec<_>>().join("_");
+        write!(output, " {:>14} {:>14}", label, format!("{}_error", label))?;
+    }
+    writeln!(output)?;
+    for i in 0..distribution.bins() {
+        write!(
+            output,
+            "{:>14.6} {:>14.6}",
+            distribution.edges[i],
+            distribution.edges[i + 1]
+        )?;
+        for series in distribution.series.iter() {
+            write!(
+                output,
+                " {:>14.6e} {:>14.6e}",
+                series.values[i], series.uncertainties[i]
+            )?;
+        }
+        writeln!(output)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{particle, phsp};
+    use crate::PHSPTrackingWriter;
+    use std::io::Cursor;
+
+    #[test]
+    fn zones_bin_by_radius_or_half_width() {
+        let annular = Zones::new(ZoneShape::Annular, 4, 2.0).unwrap();
+        assert_eq!(annular.zone(0.0, 0.0), Some(0));
+        assert_eq!(annular.zone(0.9, 0.9), Some(2));
+        assert_eq!(annular.zone(1.5, 1.5), None);
+        let square = Zones::new(ZoneShape::Square, 4, 2.0).unwrap();
+        assert_eq!(square.zone(1.5, 1.5), Some(3));
+        assert_eq!(square.zone(-2.0, 0.0), None);
+        assert_eq!(annular.zone(f32::NAN, 0.0), None);
+        assert_eq!(square.zone(0.0, f32::NAN), None);
+        let total: f64 = (0..4).map(|i| square.area(i)).sum();
+        assert!((total - 16.0).abs() < 1e-12);
+        assert!((annular.area(0) - PI * 0.25).abs() < 1e-12);
+        assert!(Zones::new(ZoneShape::Annular, 0, 2.0).is_err());
+        assert!(Zones::new(ZoneShape::Annular, 4, 0.0).is_err());
+        assert!(Zones::new(ZoneShape::Annular, 4, f64::NAN).is_err());
+    }
+
+    #[test]
+    fn fluence_is_weight_per_area_per_history() {
+        let bytes = phsp(
+            &[
+                particle(0, 1.0, 0.1, 0.0, 2.0),
+                particle(0, 1.0, 0.0, -0.2, 2.0),
+                particle(1 << 30, 2.0, 1.5, 0.0, 1.0),
+                particle(0, 1.0, 5.0, 0.0, 1.0),
+            ],
+            10.0,
+        );
+        let reader = PHSPReader::new(&bytes[..], None).unwrap();
+        let zones = Zones::new(ZoneShape::Annular, 2, 2.0).unwrap();
+        let selections = [Selection::Photons, Selection::Charged];
+        let profile = profile(reader, &zones, &selections, Quantity::Fluence).unwrap();
+        let photons = &profile.series[0];
+        assert!((photons.values[0] - 4.0 / (PI * 10.0)).abs() < 1e-12);
+        assert!((photons.uncertainties[0] - 8.0_f64.sqrt() / (PI * 10.0)).abs() < 1e-12);
+        assert_eq!(photons.values[1], 0.0);
+        let charged = &profile.series[1];
+        assert!((charged.values[1] - 1.0 / (3.0 * PI * 10.0)).abs() < 1e-12);
+
+        let mut table = Vec::new();
+        write_table(&profile, &mut table).unwrap();
+        let table = String::from_utf8(table).unwrap();
+        assert_eq!(table.lines().count(), 3 + 2);
+    }
+
+    #[test]
+    fn energy_fluence_uses_kinetic_energy() {
+        let bytes = phsp(
+            &[
+                particle(0, 2.0, 0.1, 0.0, 2.0),
+                particle(1 << 30, 1.511, 0.0, 0.1, 1.0),
+            ],
+            1.0,
+        );
+        let reader = PHSPReader::new(&bytes[..], None).unwrap();
+        let zones = Zones::new(ZoneShape::Square, 1, 1.0).unwrap();
+        let energy_fluence =
+            profile(reader, &zones, &[Selection::All], Quantity::EnergyFluence).unwrap();
+        let series = &energy_fluence.series[0];
+        assert!((series.values[0] - 5.0 / 4.0).abs() < 1e-6);
+        assert!((series.uncertainties[0] - 17.0_f64.sqrt() / 4.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn mean_energy_is_weighted_with_standard_error() {
+        let bytes = phsp(
+            &[
+                particle(0, 1.0, 0.0, 0.0, 1.0),
+                particle(0, 3.0, 0.0, 0.0, 1.0),
+                particle(1 << 30, 2.511, 0.0, 0.0, 2.0),
+                particle(0, 3.0, 1.5, 0.0, 1.0),
+            ],
+            100.0,
+        );
+        let reader = PHSPReader::new(&bytes[..], None).unwrap();
+        let zones = Zones::new(ZoneShape::Annular, 2, 2.0).unwrap();
+        let selections = [Selection::Photons, Selection::Electrons, Selection::Positrons];
+        let mean = profile(reader, &zones, &selections, Quantity::MeanEnergy).unwrap();
+        let photons = &mean.series[0];
+        assert_eq!(photons.values, vec![2.0, 3.0]);
+        // variance 1 over an effective 2 particles
+        assert!((photons.uncertainties[0] - 0.5_f64.sqrt()).abs() < 1e-12);
+        assert_eq!(photons.uncertainties[1], 0.0);
+        assert!((mean.series[1].values[0] - 2.0).abs() < 1e-6);
+        assert!(mean.series[2].values[0].is_nan());
+    }
+
+    #[test]
+    fn angular_is_per_steradian() {
+        let mut forward = particle(0, 1.0, 0.0, 0.0, 1.0);
+        forward.x_cos = 0.1;
+        let mut oblique = particle(0, 1.0, 0.0, 0.0, 1.0);
+        oblique.x_cos = 0.8;
+        let backward = particle(0, 1.0, 0.0, 0.0, -1.0);
+        assert!((polar_angle(&oblique) - 53.130102).abs() < 1e-4);
+        assert_eq!(polar_angle(&backward), 180.0);
+
+        let bytes = phsp(&[forward, oblique, backward], 1.0);
+        let reader = PHSPReader::new(&bytes[..], None).unwrap();
+        let binning = Binning::new(0.0, 180.0, 2, Scale::Linear).unwrap();
+        let distribution = angular(
+            reader,
+            &binning,
+            &Field::Everywhere,
+            &[Selection::All],
+            Quantity::Fluence,
+        )
+        .unwrap();
+        // each hemisphere is 2π sr
+        let values = &distribution.series[0].values;
+        assert!((values[0] - 2.0 / (2.0 * PI)).abs() < 1e-12);
+        assert!((values[1] - 1.0 / (2.0 * PI)).abs() < 1e-12);
+    }
+
+    #[test]
+    fn zlast_needs_mode2_and_skips_unknown() {
+        let bytes = phsp(&[particle(0, 1.0, 0.0, 0.0, 1.0)], 1.0);
+        let binning = Binning::new(0.0, 10.0, 2, Scale::Linear).unwrap();
+        let reader = PHSPReader::new(&bytes[..], None).unwrap();
+        let selections = [Selection::All];
+        let result = zlast(reader, &binning, &Field::Everywhere, &selections, Quantity::Fluence);
+        assert!(matches!(result, Err(EGSError::MissingZlast)));
+
+        let mut buffer = Cursor::new(Vec::new());
+        let mut writer = PHSPTrackingWriter::new(&mut buffer, true, 1.0).unwrap();
+        for z in [1.0, 2.0, 7.5, crate::ZLAST_UNKNOWN] {
+            let mut record = particle(0, 2.0, 0.0, 0.0, 1.0);
+            record.zlast = Some(z);
+            writer.write(&record).unwrap();
+        }
+        writer.finish().unwrap();
+        let bytes = buffer.into_inner();
+        let reader = PHSPReader::new(&bytes[..], None).unwrap();
+        let distribution =
+            zlast(reader, &binning, &Field::Everywhere, &selections, Quantity::EnergyFluence)
+                .unwrap();
+        assert_eq!(distribution.series[0].values, vec![4.0 / 5.0, 2.0 / 5.0]);
+    }
+
+    #[test]
+    fn weight_distribution_finds_fat_particles() {
+        let weights = [0.01, 0.01, 0.01, 0.01, 1.0];
+        let records: Vec<Record> = weights
+            .iter()
+            .map(|w| particle(0, 1.0, 0.0, 0.0, *w))
+            .collect();
+        let bytes = phsp(&records, 1.0);
+        let reader = PHSPReader::new(Cursor::new(bytes), None).unwrap();
+        let (distribution, summaries) =
+            weight_distribution(reader, 2, &[Selection::Photons], None).unwrap();
+        assert_eq!(distribution.series[0].values, vec![4.0, 1.0]);
+        assert!((distribution.edges[1] - 0.1).abs() < 1e-6);
+        let photons = &summaries[0];
+        assert_eq!(photons.count, 5);
+        assert_eq!(photons.fat_fraction, 0.2);
+        assert!((photons.fat_weight_fraction - 1.0 / 1.04).abs() < 1e-6);
+        assert!((photons.effective_particles - 1.04 * 1.04 / 1.0004).abs() < 1e-5);
+        assert!((photons.max_weight_fraction - 1.0 / 1.04).abs() < 1e-6);
+    }
+
+    #[test]
+    fn merge_labels_series_by_file() {
+        let bytes = phsp(&[particle(0, 1.0, 0.1, 0.0, 1.0)], 1.0);
+        let zones = Zones::new(ZoneShape::Annular, 2, 2.0).unwrap();
+        let analyse = |zones: &Zones| {
+            let reader = PHSPReader::new(&bytes[..], None).unwrap();
+            profile(reader, zones, &[Selection::All], Quantity::Fluence).unwrap()
+        };
+        let merged = Distribution::merge(vec![
+            ("a.egsphsp1".to_string(), analyse(&zones)),
+            ("b.egsphsp1".to_string(), analyse(&zones)),
+        ])
+        .unwrap();
+        let labels: Vec<&str> = merged.series.iter().map(|s| s.label.as_str()).collect();
+        assert_eq!(labels, vec!["a.egsphsp1: all", "b.egsphsp1: all"]);
+        let other = Zones::new(ZoneShape::Annular, 3, 2.0).unwrap();
+        let mismatched = Distribution::merge(vec![
+            ("a.egsphsp1".to_string(), analyse(&zones)),
+            ("c.egsphsp1".to_string(), analyse(&other)),
+        ]);
+        assert!(matches!(mismatched, Err(EGSError::BadBinning(_))));
+    }
+
+    #[test]
+    fn log_binning_includes_maximum() {
+        let binning = Binning::new(0.01, 10.0, 3, Scale::Log).unwrap();
+        let edges = binning.edges();
+        assert!((edges[1] - 0.1).abs() < 1e-12 && (edges[2] - 1.0).abs() < 1e-12);
+        assert_eq!(binning.bin(0.05), Some(0));
+        assert_eq!(binning.bin(10.0), Some(2));
+        assert_eq!(binning.bin(10.5), None);
+        assert_eq!(binning.bin(f64::NAN), None);
+        assert!(Binning::new(0.0, 1.0, 3, Scale::Log).is_err());
+        let linear = Binning::new(0.0, 1.0, 3, Scale::Linear).unwrap();
+        assert_eq!(linear.bin(0.0), Some(0));
+        assert_eq!(linear.bin(f64::NAN), None);
+    }
+
+    #[test]
+    fn lowest_energy_includes_photons() {
+        let bytes = phsp(
+            &[
+                particle(0, 0.005, 0.0, 0.0, 1.0),
+                particle(1 << 30, 0.611, 0.0, 0.0, 1.0),
+            ],
+            1.0,
+        );
+        let reader = PHSPReader::new(&bytes[..], None).unwrap();
+        assert!(reader.header.min_energy > 0.05);
+        assert_eq!(lowest_energy(reader).unwrap(), Some(0.005_f32 as f64));
+    }
+
+    #[test]
+    fn spectrum_counts_particles_in_field_per_mev() {
+        let bytes = phsp(
+            &[
+                particle(0, 0.5, 0.0, 0.0, 1.0),
+                particle(0, 1.5, 0.5, 0.5, 3.0),
+                particle(0, 1.5, 5.0, 0.0, 1.0),
+                particle(1 << 29, 1.011, 0.0, 0.0, 1.0),
+            ],
+            2.0,
+        );
+        let reader = PHSPReader::new(&bytes[..], None).unwrap();
+        let binning = Binning::new(0.0, 2.0, 4, Scale::Linear).unwrap();
+        let field = Field::Rectangle {
+            x_min: -1.0,
+            x_max: 1.0,
+            y_min: -1.0,
+            y_max: 1.0,
+        };
+        let selections = [Selection::Photons, Selection::Positrons];
+        let spectrum =
+            spectrum(reader, &binning, &field, &selections, Quantity::Fluence).unwrap();
+        // bins are 0.5 MeV wide, the field is 4 cm² and there were 2 histories
+        let photons = &spectrum.series[0].values;
+        assert_eq!(photons, &vec![0.0, 0.25, 0.0, 0.75]);
+        assert_eq!(spectrum.series[1].values, vec![0.0, 0.25, 0.0, 0.0]);
+    }
+}
@@ -1,13 +1,35 @@
 use std::f32;
 use std::fs::File;
+use std::io;
 use std::path::Path;
 use std::process::exit;
 
-use clap::{value_parser, Arg, Command};
+use clap::{value_parser, Arg, ArgMatches, Command};
+use serde::Serialize;
 
+use egsphsp::analysis::{
+    angular, lowest_energy, profile, spectrum, weight_distribution, write_table, zlast, Binning,
+    Distribution, Field, Quantity, Scale, Selection, ZoneShape, Zones,
+};
+use egsphsp::curve::Curve;
+use egsphsp::expr::Expr;
+use egsphsp::filter::{filter, Direction, Filter};
+use egsphsp::iaea::{egsphsp_to_iaea, iaea_to_egsphsp, is_iaea_path};
+use egsphsp::map::{
+    fluence_map, read_matrix, read_pgm, reweight_map, write_matrix, write_pgm, write_ppm,
+    write_scatter_svg, Grid, Map,
+};
+use egsphsp::plot::{write_svg, PlotOptions, Style};
+use egsphsp::npy::{export_npy, export_npz};
+use egsphsp::stats::{scan_reader, ParticleStats, Stats};
+use egsphsp::table::{export_table, import_table, TableFormat};
+use egsphsp::validate::validate;
+use egsphsp::xmgrace::write_agr;
 use egsphsp::PHSPReader;
 use egsphsp::{
-    combine, compare, randomize, reweight, sample_combine, transform, translate, Transform,
+    combine, compare, convert_mode, randomize, repair, reweight, reweight_records,
+    reweight_spectrum, sample_combine, slice, transform, translate, EGSError, EGSResult,
+    Transform,
 };
 
 fn main() {
@@ -26,7 +48,10 @@ fn main() {
                 .value_name("FIELDS")
                 .value_parser(value_parser!(String))
                 .required(true)
-                .num_args(1..))
+                .num_args(1..)
+                .help("Fields to print - energy, weight, x, y, x_cos, y_cos, r, produced, \
+                       charged, or any expression like \"e - 0.511\""))
+            .arg(where_arg())
             .arg(Arg::new("number")
                 .long("number")
                 .short('n')
@@ -37,8 +62,43 @@ fn main() {
                 .value_name("FILE")
                 .value_parser(value_parser!(String))
                 .required(true)))
+        .subcommand(Command::new("export")
+            .about("Write every record as a row of a CSV or TSV table (latch, energy, x, y, u, v, w, \
+                    weight, zlast, charge, region, first_scored), or as one NumPy array per field")
+            .arg(Arg::new("input")
+                .help("Phase space file")
+                .required(true))
+            .arg(Arg::new("output")
+                .help("Output table (- for standard output), directory of .npy files, or .npz \
+                       archive")
+                .required(true))
+            .arg(Arg::new("format")
+                .long("format")
+                .default_value("csv")
+                .value_parser(["csv", "tsv", "npy", "npz"])
+                .help("Output format")))
+        .subcommand(Command::new("import")
+            .about("Build a phase space file from a CSV or TSV table with a header row - \
+                    energy, x, y, u, v and weight columns are required")
+            .arg(Arg::new("input")
+                .help("Input table")
+                .required(true))
+            .arg(Arg::new("output")
+                .help("Output phase space file")
+                .required(true))
+            .arg(Arg::new("format")
+                .long("format")
+                .default_value("csv")
+                .value_parser(["csv", "tsv"])
+                .help("Table format"))
+            .arg(Arg::new("source")
+                .long("source")
+                .value_name("PARTICLES")
+                .value_parser(value_parser!(f32))
+                .help("Number of incident particles from source (defaults to the number of rows)")))
         .subcommand(Command::new("reweight")
-            .about("Reweight a phase space file as a function of distance from z")
+            .about("Reweight a phase space file so its radial fluence follows a target profile, \
+                    or by any expression of the particle")
             .arg(Arg::new("input")
                 .required(true)
                 .value_name("INPUT")
@@ -50,23 +110,137 @@ fn main() {
                 .value_name("OUTPUT")
                 .value_parser(value_parser!(String)))
             .arg(Arg::new("r")
-                .required(true)
+                .required_unless_present("weight-expr")
                 .short('r')
                 .value_name("RADIUS")
                 .value_parser(value_parser!(f32))
-                .allow_hyphen_values(true))
+                .allow_hyphen_values(true)
+                .help("Radius of the binned profile - particles further out are unchanged"))
             .arg(Arg::new("c")
                 .short('c')
                 .value_name("CONSTANT")
                 .value_parser(value_parser!(f32))
                 .allow_hyphen_values(true)
-                .required(true))
+                .required_unless_present_any(["weight-expr", "target", "target-expr"])
+                .help("Target fluence CONSTANT * r"))
+            .arg(Arg::new("target")
+                .long("target")
+                .value_name("FILE")
+                .conflicts_with_all(["c", "target-expr"])
+                .help("Target fluence profile as two columns of radius (cm) and fluence"))
+            .arg(Arg::new("target-expr")
+                .long("target-expr")
+                .value_name("EXPRESSION")
+                .value_parser(|s: &str| s.parse::<Expr>())
+                .conflicts_with("c")
+                .help("Target fluence as an expression of r, e.g. \"1 + 0.02 * r^2\""))
+            .arg(Arg::new("weight-expr")
+                .long("weight-expr")
+                .value_name("EXPRESSION")
+                .value_parser(|s: &str| s.parse::<Expr>())
+                .conflicts_with_all(["r", "c", "target", "target-expr"])
+                .help("Multiply weights by this expression of the particle, e.g. \"exp(-r/5)\""))
             .arg(Arg::new("bins")
                 .long("bins")
                 .value_name("BINS")
                 .value_parser(value_parser!(String))
                 .default_value("100")
-                .required(false)))
+                .required(false)
+                .help("Number of rings in the binned profile")))
+        .subcommand(Command::new("reweight-spectrum")
+            .about("Reweight each particle type so its kinetic energy spectrum follows a target \
+                    spectrum, keeping the total weight of each type")
+            .arg(Arg::new("input")
+                .help("Phase space file")
+                .required(true))
+            .arg(Arg::new("output")
+                .short('o')
+                .long("output")
+                .help("Write a new file instead of changing the input"))
+            .arg(Arg::new("target")
+                .long("target")
+                .value_name("FILE")
+                .required(true)
+                .help("Target spectrum as two columns of kinetic energy (MeV) and particles per \
+                       MeV - only its shape matters"))
+            .arg(Arg::new("particle")
+                .short('p')
+                .long("particle")
+                .value_parser(["all", "photons", "electrons", "positrons", "charged"])
+                .default_value("photons")
+                .value_delimiter(',')
+                .help("Comma separated particle types, each reshaped separately"))
+            .arg(Arg::new("bins")
+                .long("bins")
+                .value_name("BINS")
+                .value_parser(value_parser!(usize))
+                .default_value("50"))
+            .arg(Arg::new("log")
+                .long("log")
+                .action(clap::ArgAction::SetTrue)
+                .help("Bins of equal width in log energy"))
+            .arg(Arg::new("min-energy")
+                .long("min-energy")
+                .value_name("MEV")
+                .value_parser(value_parser!(f64))
+                .help("Lower edge of the first bin, by default 0 or for --log the lowest \
+                       particle energy"))
+            .arg(Arg::new("max-energy")
+                .long("max-energy")
+                .value_name("MEV")
+                .value_parser(value_parser!(f64))
+                .help("Upper edge of the last bin instead of the header maximum")))
+        .subcommand(Command::new("reweight-map")
+            .about("Multiply weights by a 2D map, such as the transmission of a wedge or block, \
+                    looked up at each particle's position")
+            .arg(Arg::new("input")
+                .help("Phase space file")
+                .required(true))
+            .arg(Arg::new("output")
+                .short('o')
+                .long("output")
+                .help("Write a new file instead of changing the input"))
+            .arg(Arg::new("window")
+                .long("window")
+                .value_names(["X_MIN", "X_MAX", "Y_MIN", "Y_MAX"])
+                .value_parser(value_parser!(f32))
+                .num_args(4)
+                .allow_hyphen_values(true)
+                .required(true)
+                .help("Region of the plane the maps cover, in cm"))
+            .arg(Arg::new("map")
+                .long("map")
+                .value_name("FILE")
+                .help("Map for every particle - a PGM image (.pgm, white is 1) or a text matrix, \
+                       top row first"))
+            .arg(Arg::new("photon-map")
+                .long("photon-map")
+                .value_name("FILE")
+                .help("Map for photons, instead of --map"))
+            .arg(Arg::new("electron-map")
+                .long("electron-map")
+                .value_name("FILE")
+                .help("Map for electrons, instead of --map"))
+            .arg(Arg::new("positron-map")
+                .long("positron-map")
+                .value_name("FILE")
+                .help("Map for positrons, instead of --map"))
+            .group(clap::ArgGroup::new("maps")
+                .args(["map", "photon-map", "electron-map", "positron-map"])
+                .multiple(true)
+                .required(true))
+            .arg(Arg::new("project")
+                .long("project")
+                .value_name("CM")
+                .value_parser(value_parser!(f32))
+                .allow_hyphen_values(true)
+                .help("Look up particles where they cross the plane this far downstream"))
+            .arg(Arg::new("outside")
+                .long("outside")
+                .value_name("VALUE")
+                .value_parser(value_parser!(f64))
+                .default_value("1")
+                .help("Factor for particles that miss the maps")))
         .subcommand(Command::new("randomize")
             .about("Randomize the order of the particles")
             .arg(Arg::new("input").required(true))
@@ -86,7 +260,294 @@ fn main() {
                 .default_value("human")
                 .value_parser(["human", "json"])
                 .long("format")
-                .help("Output stats in json or human format")))
+                .help("Output stats in json or human format"))
+            .arg(Arg::new("scan")
+                .long("scan")
+                .short('s')
+                .action(clap::ArgAction::SetTrue)
+                .help("Read every record for per-particle statistics, not just the header")))
+        .subcommand(profile_command("fluence-profile", "all")
+            .about("Particle fluence (weight per unit area, per incident particle) in annular or \
+                    square zones, with uncertainties"))
+        .subcommand(profile_command("energy-fluence-profile", "all")
+            .about("Energy fluence (weight times kinetic energy per unit area, per incident \
+                    particle) in annular or square zones, with uncertainties"))
+        .subcommand(profile_command("mean-energy-profile", "photons,electrons,positrons")
+            .about("Weighted mean kinetic energy in annular or square zones, with its \
+                    standard error"))
+        .subcommand(field_args(analysis_command("angular", "photons,electrons,positrons"))
+            .about("Fluence (or energy fluence) per steradian in bins of polar angle from the z \
+                    axis, per particle type")
+            .arg(Arg::new("bins")
+                .long("bins")
+                .value_name("BINS")
+                .value_parser(value_parser!(usize))
+                .default_value("90"))
+            .arg(Arg::new("max-angle")
+                .long("max-angle")
+                .value_name("DEGREES")
+                .value_parser(value_parser!(f64))
+                .default_value("90")
+                .help("Upper edge of the last bin - 180 includes backward moving particles"))
+            .arg(Arg::new("energy-fluence")
+                .long("energy-fluence")
+                .action(clap::ArgAction::SetTrue)
+                .help("Weight each particle by its kinetic energy")))
+        .subcommand(field_args(analysis_command("zlast", "photons,electrons,positrons"))
+            .about("Fluence (or energy fluence) per cm of ZLAST, the z of the last interaction, \
+                    per particle type - MODE2 files only")
+            .arg(Arg::new("bins")
+                .long("bins")
+                .value_name("BINS")
+                .value_parser(value_parser!(usize))
+                .default_value("100"))
+            .arg(Arg::new("min-z")
+                .long("min-z")
+                .value_name("CM")
+                .value_parser(value_parser!(f64))
+                .allow_hyphen_values(true)
+                .default_value("0"))
+            .arg(Arg::new("max-z")
+                .long("max-z")
+                .value_name("CM")
+                .value_parser(value_parser!(f64))
+                .allow_hyphen_values(true)
+                .required(true)
+                .help("Upper edge of the last bin, usually the z of the scoring plane"))
+            .arg(Arg::new("energy-fluence")
+                .long("energy-fluence")
+                .action(clap::ArgAction::SetTrue)
+                .help("Weight each particle by its kinetic energy")))
+        .subcommand(analysis_command("weights", "photons,electrons,positrons")
+            .mut_arg("input", |arg| arg.num_args(1).help("Phase space file"))
+            .about("Number of particles in log bins of |weight| per particle type, with the \
+                    fat particle fraction, effective sample size and heaviest particle")
+            .arg(Arg::new("bins")
+                .long("bins")
+                .value_name("BINS")
+                .value_parser(value_parser!(usize))
+                .default_value("40"))
+            .arg(Arg::new("fat-weight")
+                .long("fat-weight")
+                .value_name("WEIGHT")
+                .value_parser(value_parser!(f64))
+                .help("Particles heavier than this are fat (default twice the smallest weight)")))
+        .subcommand(Command::new("map")
+            .about("Bin particles onto an x/y grid as a text matrix, PGM or PPM image, and plot \
+                    the first particles as an SVG scatter colored by type")
+            .arg(Arg::new("input")
+                .help("Phase space file")
+                .required(true))
+            .arg(Arg::new("window")
+                .long("window")
+                .value_names(["X_MIN", "X_MAX", "Y_MIN", "Y_MAX"])
+                .value_parser(value_parser!(f32))
+                .num_args(4)
+                .allow_hyphen_values(true)
+                .required(true)
+                .help("Region of the scoring plane to map, in cm"))
+            .arg(Arg::new("resolution")
+                .long("resolution")
+                .value_name("CM")
+                .value_parser(value_parser!(f32))
+                .default_value("0.1")
+                .help("Pixel size"))
+            .arg(Arg::new("quantity")
+                .long("quantity")
+                .value_parser(["fluence", "energy-fluence", "count"])
+                .default_value("fluence")
+                .help("Fluence or energy fluence per incident particle, or number of particles"))
+            .arg(Arg::new("particle")
+                .short('p')
+                .long("particle")
+                .value_parser(["all", "photons", "electrons", "positrons", "charged"])
+                .default_value("all"))
+            .arg(Arg::new("matrix")
+                .long("matrix")
+                .value_name("FILE")
+                .help("Write the grid as a text matrix (the default, to standard output)"))
+            .arg(Arg::new("pgm")
+                .long("pgm")
+                .value_name("FILE")
+                .help("Write the grid as a greyscale PGM image"))
+            .arg(Arg::new("ppm")
+                .long("ppm")
+                .value_name("FILE")
+                .help("Write the grid as a color PPM image"))
+            .arg(Arg::new("scatter")
+                .long("scatter")
+                .value_name("FILE")
+                .help("Write an SVG scatter plot of particle positions"))
+            .arg(Arg::new("scatter-count")
+                .long("scatter-count")
+                .value_name("PARTICLES")
+                .value_parser(value_parser!(usize))
+                .default_value("10000")
+                .help("Number of particles in the scatter plot")))
+        .subcommand(field_args(analysis_command("spectrum", "photons,electrons,positrons"))
+            .about("Fluence (or energy fluence) per MeV in kinetic energy bins up to the header \
+                    maximum energy, per particle type")
+            .arg(Arg::new("bins")
+                .long("bins")
+                .value_name("BINS")
+                .value_parser(value_parser!(usize))
+                .default_value("50"))
+            .arg(Arg::new("log")
+                .long("log")
+                .action(clap::ArgAction::SetTrue)
+                .help("Bins of equal width in log energy"))
+            .arg(Arg::new("min-energy")
+                .long("min-energy")
+                .value_name("MEV")
+                .value_parser(value_parser!(f64))
+                .help("Lower edge of the first bin, by default 0 or for --log the lowest \
+                       particle energy"))
+            .arg(Arg::new("max-energy")
+                .long("max-energy")
+                .value_name("MEV")
+                .value_parser(value_parser!(f64))
+                .help("Upper edge of the last bin instead of the header maximum"))
+            .arg(Arg::new("energy-fluence")
+                .long("energy-fluence")
+                .action(clap::ArgAction::SetTrue)
+                .help("Weight each particle by its kinetic energy")))
+        .subcommand(Command::new("validate")
+            .about("Check the file length, header totals and every record for consistency - \
+                    exits non-zero if any problem is found")
+            .arg(Arg::new("input")
+                .help("Phase space file")
+                .required(true)))
+        .subcommand(Command::new("repair")
+            .about("Recompute the header from the records, dropping a trailing partial record")
+            .arg(Arg::new("input")
+                .help("Phase space file")
+                .required(true))
+            .arg(Arg::new("output")
+                .long("output")
+                .short('o')
+                .value_name("OUTPUT")
+                .value_parser(value_parser!(String))
+                .help("Write the repaired file here instead of repairing in place"))
+            .arg(Arg::new("source")
+                .long("source")
+                .value_name("PARTICLES")
+                .value_parser(value_parser!(f32))
+                .help("Set the number of incident particles from source (kept otherwise)")))
+        .subcommand(Command::new("slice")
+            .about("Extract a range of records into a new phase space file")
+            .arg(Arg::new("input")
+                .help("Phase space file")
+                .required(true))
+            .arg(Arg::new("output")
+                .help("Output file")
+                .required(true))
+            .arg(Arg::new("start")
+                .long("start")
+                .value_name("RECORD")
+                .value_parser(value_parser!(u64))
+                .default_value("0")
+                .help("Index of the first record to extract (zero based)"))
+            .arg(Arg::new("count")
+                .long("count")
+                .short('n')
+                .value_name("RECORDS")
+                .value_parser(value_parser!(u64))
+                .required(true)
+                .help("Number of records to extract")))
+        .subcommand(field_args(Command::new("filter")
+            .about("Keep only the particles matching every given condition - the number of \
+                    particles in the source is kept")
+            .arg(Arg::new("input")
+                .help("Phase space file")
+                .required(true))
+            .arg(Arg::new("output")
+                .help("Output file (may be the input file)")
+                .required(true))
+            .arg(Arg::new("particle")
+                .short('p')
+                .long("particle")
+                .value_parser(["all", "photons", "electrons", "positrons", "charged"])
+                .value_delimiter(',')
+                .help("Comma separated particle types to keep"))
+            .arg(Arg::new("min-energy")
+                .long("min-energy")
+                .value_name("MEV")
+                .value_parser(value_parser!(f32))
+                .help("Smallest total energy to keep"))
+            .arg(Arg::new("max-energy")
+                .long("max-energy")
+                .value_name("MEV")
+                .value_parser(value_parser!(f32))
+                .help("Largest total energy to keep"))
+            .arg(Arg::new("max-angle")
+                .long("max-angle")
+                .value_name("DEGREES")
+                .value_parser(value_parser!(f64))
+                .help("Largest angle to the +z axis to keep"))
+            .arg(Arg::new("direction")
+                .long("direction")
+                .value_parser(["forward", "backward"])
+                .help("Keep only particles going in this z direction"))
+            .arg(Arg::new("region")
+                .long("region")
+                .value_parser(value_parser!(u32))
+                .value_delimiter(',')
+                .help("Comma separated latch region numbers to keep"))
+            .arg(Arg::new("latch-set")
+                .long("latch-set")
+                .value_name("MASK")
+                .value_parser(parse_mask)
+                .default_value("0")
+                .help("Keep only particles with all of these latch bits set (0x for hex)"))
+            .arg(Arg::new("latch-clear")
+                .long("latch-clear")
+                .value_name("MASK")
+                .value_parser(parse_mask)
+                .default_value("0")
+                .help("Keep only particles with none of these latch bits set (0x for hex)"))
+            .arg(where_arg())))
+        .subcommand(Command::new("convert")
+            .about("Convert between egsphsp and IAEA (.IAEAheader/.IAEAphsp) phase space files - \
+                    the direction is chosen from the input extension")
+            .arg(Arg::new("input")
+                .help("Phase space file (IAEA files may be given by either file of the pair)")
+                .required(true))
+            .arg(Arg::new("output")
+                .help("Output file")
+                .required(true))
+            .arg(Arg::new("z")
+                .short('z')
+                .value_name("Z")
+                .value_parser(value_parser!(f32))
+                .allow_hyphen_values(true)
+                .default_value("0")
+                .help("Z of the scoring plane in centimeters, stored in the IAEA header")))
+        .subcommand(Command::new("convert-mode")
+            .about("Rewrite a phase space file as MODE0 (dropping ZLAST) or MODE2")
+            .arg(Arg::new("mode")
+                .long("mode")
+                .short('m')
+                .value_parser(["0", "2", "MODE0", "MODE2"])
+                .required(true)
+                .help("Mode of the output file"))
+            .arg(Arg::new("zlast")
+                .long("zlast")
+                .value_name("Z")
+                .value_parser(value_parser!(f32))
+                .allow_hyphen_values(true)
+                .help("ZLAST in centimeters for every particle when converting MODE0 to MODE2 \
+                       (unknown, stored as NaN, if not given)"))
+            .arg(Arg::new("in-place")
+                .short('i')
+                .long("in-place")
+                .help("Convert input file in-place")
+                .action(clap::ArgAction::SetTrue))
+            .arg(Arg::new("input")
+                .help("Phase space file")
+                .required(true))
+            .arg(Arg::new("output")
+                .help("Output file")
+                .required_unless_present("in-place")))
         .subcommand(Command::new("combine")
             .about("Combine phase space from one or more input files into outputfile - does not \
                     adjust weights")
@@ -239,13 +700,34 @@ fn main() {
             .unwrap()
             .map(|s| s.as_str())
             .collect();
+        // anything that isn't a field name is an expression
+        let mut expressions = Vec::new();
+        for field in fields.iter() {
+            let known = [
+                "weight", "energy", "x", "y", "x_cos", "y_cos", "produced", "charged", "r",
+            ];
+            if !known.contains(field) {
+                match field.parse::<Expr>() {
+                    Ok(expression) => expressions.push(expression),
+                    Err(message) => {
+                        println!("Error: Invalid field {}: {}", field, message);
+                        exit(1);
+                    }
+                }
+            }
+        }
+        let condition = sub_matches.get_one::<Expr>("where");
         let file = File::open(input_path).unwrap();
         let reader = PHSPReader::from(file).unwrap();
         for field in fields.iter() {
             print!("{:<16}", field);
         }
         println!();
-        for record in reader.take(number).map(|r| r.unwrap()) {
+        let records = reader
+            .map(|r| r.unwrap())
+            .filter(|record| condition.is_none_or(|condition| condition.matches(record)));
+        for record in records.take(number) {
+            let mut expressions = expressions.iter();
             for field in fields.iter() {
                 match *field {
                     "weight" => print!("{:<16}", record.get_weight()),
@@ -260,33 +742,145 @@ fn main() {
                         "{:<16}",
                         (record.x_cm * record.x_cm + record.y_cm * record.y_cm).sqrt()
                     ),
-                    _ => panic!("Unknown field {}", field),
+                    _ => print!("{:<16}", expressions.next().unwrap().eval(&record) as f32),
                 };
             }
             println!();
         }
         Ok(())
+    } else if subcommand == "export" || subcommand == "import" {
+        let sub_matches = matches.subcommand_matches(subcommand).unwrap();
+        let input_path = Path::new(sub_matches.get_one::<String>("input").unwrap());
+        let output = sub_matches.get_one::<String>("output").unwrap();
+        let format = match sub_matches.get_one::<String>("format").unwrap().as_str() {
+            "tsv" => TableFormat::Tsv,
+            _ => TableFormat::Csv,
+        };
+        let npy_format = sub_matches.get_one::<String>("format").unwrap();
+        if subcommand == "export" && npy_format == "npy" {
+            export_npy(input_path, Path::new(output))
+        } else if subcommand == "export" && npy_format == "npz" {
+            export_npz(input_path, Path::new(output))
+        } else if subcommand == "import" {
+            let source = sub_matches.get_one::<f32>("source").copied();
+            import_table(input_path, Path::new(output), format, source)
+        } else if output == "-" {
+            export_table(input_path, io::stdout().lock(), format)
+        } else {
+            File::create(output)
+                .map_err(EGSError::from)
+                .and_then(|file| export_table(input_path, file, format))
+        }
     } else if subcommand == "reweight" {
-        println!("unwrapping subcommand");
         let sub_matches = matches.subcommand_matches("reweight").unwrap();
-        println!("unwrapping input_path");
         let input_path = Path::new(sub_matches.get_one::<String>("input").unwrap());
-        println!("unwrapping output_path");
         let output_path = if sub_matches.contains_id("output") {
             Path::new(sub_matches.get_one::<String>("output").unwrap())
         } else {
             input_path
         };
-        println!("unwrapping c");
-        let c = *sub_matches.get_one::<f32>("c").unwrap();
-        println!("unwrapping r");
-        let r = *sub_matches.get_one::<f32>("r").unwrap();
-        let bins = sub_matches
-            .get_one::<String>("bins")
-            .unwrap()
-            .parse::<usize>()
-            .unwrap();
-        reweight(input_path, output_path, &|x| c * x, bins, r)
+        if let Some(expression) = sub_matches.get_one::<Expr>("weight-expr") {
+            reweight_records(input_path, output_path, &|record| expression.eval(record) as f32)
+        } else {
+            let r = *sub_matches.get_one::<f32>("r").unwrap();
+            let bins = sub_matches
+                .get_one::<String>("bins")
+                .unwrap()
+                .parse::<usize>()
+                .unwrap();
+            if let Some(path) = sub_matches.get_one::<String>("target") {
+                Curve::open(Path::new(path)).and_then(|curve| {
+                    reweight(input_path, output_path, &|x| curve.value(x as f64) as f32, bins, r)
+                })
+            } else if let Some(expression) = sub_matches.get_one::<Expr>("target-expr") {
+                let target = |x: f32| expression.eval_at_radius(x as f64) as f32;
+                reweight(input_path, output_path, &target, bins, r)
+            } else {
+                let c = *sub_matches.get_one::<f32>("c").unwrap();
+                reweight(input_path, output_path, &|x| c * x, bins, r)
+            }
+        }
+    } else if subcommand == "reweight-spectrum" {
+        let sub_matches = matches.subcommand_matches(subcommand).unwrap();
+        let input_path = Path::new(sub_matches.get_one::<String>("input").unwrap());
+        let output_path = match sub_matches.get_one::<String>("output") {
+            Some(output) => Path::new(output),
+            None => input_path,
+        };
+        let target_path = Path::new(sub_matches.get_one::<String>("target").unwrap());
+        let scale = if sub_matches.get_flag("log") {
+            Scale::Log
+        } else {
+            Scale::Linear
+        };
+        let selections = selections_from(sub_matches);
+        energy_binning(sub_matches, &[input_path], scale)
+            .and_then(|binning| {
+                let target = Curve::open(target_path)?;
+                reweight_spectrum(
+                    input_path,
+                    output_path,
+                    &|e| target.value(e),
+                    &binning,
+                    &selections,
+                )
+            })
+            .map(|changes| {
+                for change in changes {
+                    if change.before.is_nan() {
+                        println!("{}: no particles", change.selection);
+                        continue;
+                    }
+                    println!(
+                        "{}: effective particles {:.1} -> {:.1}",
+                        change.selection, change.before, change.after
+                    );
+                    if change.outside_weight > 0.0 {
+                        println!(
+                            "{}: weight {:.6} outside the bins was left unchanged",
+                            change.selection, change.outside_weight
+                        );
+                    }
+                }
+            })
+    } else if subcommand == "reweight-map" {
+        let sub_matches = matches.subcommand_matches(subcommand).unwrap();
+        let input_path = Path::new(sub_matches.get_one::<String>("input").unwrap());
+        let output_path = match sub_matches.get_one::<String>("output") {
+            Some(output) => Path::new(output),
+            None => input_path,
+        };
+        let window: Vec<f32> = sub_matches.get_many::<f32>("window").unwrap().copied().collect();
+        // the maps for single particle types come first, so they win over --map
+        let maps: EGSResult<Vec<(Selection, Map)>> = [
+            ("photon-map", Selection::Photons),
+            ("electron-map", Selection::Electrons),
+            ("positron-map", Selection::Positrons),
+            ("map", Selection::All),
+        ]
+        .iter()
+        .filter_map(|(name, selection)| {
+            sub_matches.get_one::<String>(name).map(|path| {
+                let path = Path::new(path);
+                let file = File::open(path)?;
+                let map = if path.extension().is_some_and(|e| e.eq_ignore_ascii_case("pgm")) {
+                    read_pgm(file, window[0], window[1], window[2], window[3])?
+                } else {
+                    read_matrix(file, window[0], window[1], window[2], window[3])?
+                };
+                Ok((*selection, map))
+            })
+        })
+        .collect();
+        maps.and_then(|maps| {
+            reweight_map(
+                input_path,
+                output_path,
+                &maps,
+                sub_matches.get_one::<f32>("project").copied(),
+                *sub_matches.get_one::<f64>("outside").unwrap(),
+            )
+        })
     } else if subcommand == "sample-combine" {
         let sub_matches = matches.subcommand_matches("sample-combine").unwrap();
         let input_paths: Vec<&Path> = sub_matches
@@ -329,34 +923,290 @@ fn main() {
         let path1 = Path::new(sub_matches.get_one::<String>("first").unwrap());
         let path2 = Path::new(sub_matches.get_one::<String>("second").unwrap());
         compare(path1, path2)
+    } else if subcommand.ends_with("-profile") {
+        let sub_matches = matches.subcommand_matches(subcommand).unwrap();
+        let quantity = match subcommand {
+            "energy-fluence-profile" => Quantity::EnergyFluence,
+            "mean-energy-profile" => Quantity::MeanEnergy,
+            _ => Quantity::Fluence,
+        };
+        let selections = selections_from(sub_matches);
+        zones_from(sub_matches)
+            .and_then(|zones| {
+                analyse_inputs(sub_matches, |reader| {
+                    profile(reader, &zones, &selections, quantity)
+                })
+            })
+            .and_then(|distribution| write_distribution(&distribution, sub_matches))
+    } else if subcommand == "spectrum" {
+        let sub_matches = matches.subcommand_matches(subcommand).unwrap();
+        let quantity = if sub_matches.get_flag("energy-fluence") {
+            Quantity::EnergyFluence
+        } else {
+            Quantity::Fluence
+        };
+        let scale = if sub_matches.get_flag("log") {
+            Scale::Log
+        } else {
+            Scale::Linear
+        };
+        let selections = selections_from(sub_matches);
+        let field = field_from(sub_matches);
+        // one set of bins covering the energies of every file
+        let paths: Vec<&Path> = sub_matches
+            .get_many::<String>("input")
+            .unwrap()
+            .map(Path::new)
+            .collect();
+        energy_binning(sub_matches, &paths, scale)
+            .and_then(|binning| {
+                analyse_inputs(sub_matches, |reader| {
+                    spectrum(reader, &binning, &field, &selections, quantity)
+                })
+            })
+            .and_then(|distribution| write_distribution(&distribution, sub_matches))
+    } else if subcommand == "angular" {
+        let sub_matches = matches.subcommand_matches(subcommand).unwrap();
+        let quantity = if sub_matches.get_flag("energy-fluence") {
+            Quantity::EnergyFluence
+        } else {
+            Quantity::Fluence
+        };
+        let selections = selections_from(sub_matches);
+        Binning::new(
+            0.0,
+            *sub_matches.get_one::<f64>("max-angle").unwrap(),
+            *sub_matches.get_one::<usize>("bins").unwrap(),
+            Scale::Linear,
+        )
+        .and_then(|binning| {
+            let field = field_from(sub_matches);
+            analyse_inputs(sub_matches, |reader| {
+                angular(reader, &binning, &field, &selections, quantity)
+            })
+        })
+        .and_then(|distribution| write_distribution(&distribution, sub_matches))
+    } else if subcommand == "zlast" {
+        let sub_matches = matches.subcommand_matches(subcommand).unwrap();
+        let quantity = if sub_matches.get_flag("energy-fluence") {
+            Quantity::EnergyFluence
+        } else {
+            Quantity::Fluence
+        };
+        let selections = selections_from(sub_matches);
+        Binning::new(
+            *sub_matches.get_one::<f64>("min-z").unwrap(),
+            *sub_matches.get_one::<f64>("max-z").unwrap(),
+            *sub_matches.get_one::<usize>("bins").unwrap(),
+            Scale::Linear,
+        )
+        .and_then(|binning| {
+            let field = field_from(sub_matches);
+            analyse_inputs(sub_matches, |reader| {
+                zlast(reader, &binning, &field, &selections, quantity)
+            })
+        })
+        .and_then(|distribution| write_distribution(&distribution, sub_matches))
+    } else if subcommand == "weights" {
+        let sub_matches = matches.subcommand_matches(subcommand).unwrap();
+        let input_path = Path::new(sub_matches.get_one::<String>("input").unwrap());
+        let selections = selections_from(sub_matches);
+        PHSPReader::open(input_path)
+            .and_then(|reader| {
+                weight_distribution(
+                    reader,
+                    *sub_matches.get_one::<usize>("bins").unwrap(),
+                    &selections,
+                    sub_matches.get_one::<f64>("fat-weight").copied(),
+                )
+            })
+            .and_then(|(distribution, summaries)| {
+                // comments, so standard output is still a valid table
+                for summary in summaries.iter() {
+                    println!(
+                        "# {}: {} particles, total weight {:.6e}, weights {:.4e} to {:.4e}",
+                        summary.label,
+                        summary.count,
+                        summary.total_weight,
+                        summary.min_weight,
+                        summary.max_weight
+                    );
+                    println!(
+                        "#     fat particles {:.4} of particles, {:.4} of weight, \
+                         effective particles {:.1}, heaviest {:.4e} of total",
+                        summary.fat_fraction,
+                        summary.fat_weight_fraction,
+                        summary.effective_particles,
+                        summary.max_weight_fraction
+                    );
+                }
+                write_distribution(&distribution, sub_matches)
+            })
+    } else if subcommand == "map" {
+        let sub_matches = matches.subcommand_matches(subcommand).unwrap();
+        let input_path = Path::new(sub_matches.get_one::<String>("input").unwrap());
+        let window: Vec<f32> = sub_matches.get_many::<f32>("window").unwrap().copied().collect();
+        let selection: Selection = sub_matches
+            .get_one::<String>("particle")
+            .unwrap()
+            .parse()
+            .unwrap();
+        let quantity = match sub_matches.get_one::<String>("quantity").unwrap().as_str() {
+            "energy-fluence" => Quantity::EnergyFluence,
+            "count" => Quantity::Count,
+            _ => Quantity::Fluence,
+        };
+        let output = |name: &str| sub_matches.get_one::<String>(name);
+        let images = ["matrix", "pgm", "ppm"].iter().any(|name| output(name).is_some());
+        // with nowhere else to go, the matrix is printed
+        let to_stdout = !images && output("scatter").is_none();
+        Grid::new(
+            window[0],
+            window[1],
+            window[2],
+            window[3],
+            *sub_matches.get_one::<f32>("resolution").unwrap(),
+        )
+        .and_then(|grid| {
+            if images || to_stdout {
+                let map = fluence_map(PHSPReader::open(input_path)?, &grid, selection, quantity)?;
+                match output("matrix") {
+                    Some(path) => write_matrix(&map, File::create(path)?)?,
+                    None if to_stdout => write_matrix(&map, io::stdout().lock())?,
+                    None => (),
+                }
+                if let Some(path) = output("pgm") {
+                    write_pgm(&map, File::create(path)?)?;
+                }
+                if let Some(path) = output("ppm") {
+                    write_ppm(&map, File::create(path)?)?;
+                }
+            }
+            if let Some(path) = output("scatter") {
+                let count = *sub_matches.get_one::<usize>("scatter-count").unwrap();
+                let reader = PHSPReader::open(input_path)?;
+                write_scatter_svg(reader, &grid, selection, count, File::create(path)?)?;
+            }
+            Ok(())
+        })
+    } else if subcommand == "validate" {
+        let sub_matches = matches.subcommand_matches("validate").unwrap();
+        let path = Path::new(sub_matches.get_one::<String>("input").unwrap());
+        match validate(path) {
+            Ok(problems) if problems.is_empty() => {
+                println!("{} is valid", path.display());
+                Ok(())
+            }
+            Ok(problems) => {
+                for problem in problems.iter() {
+                    println!("{}", problem);
+                }
+                Err(EGSError::Invalid(problems.len()))
+            }
+            Err(err) => Err(err),
+        }
+    } else if subcommand == "repair" {
+        let sub_matches = matches.subcommand_matches("repair").unwrap();
+        let input_path = Path::new(sub_matches.get_one::<String>("input").unwrap());
+        let output_path = match sub_matches.get_one::<String>("output") {
+            Some(output) => Path::new(output),
+            None => input_path,
+        };
+        let source = sub_matches.get_one::<f32>("source").copied();
+        repair(input_path, output_path, source).map(|_| ())
+    } else if subcommand == "slice" {
+        let sub_matches = matches.subcommand_matches("slice").unwrap();
+        let input_path = Path::new(sub_matches.get_one::<String>("input").unwrap());
+        let output_path = Path::new(sub_matches.get_one::<String>("output").unwrap());
+        let start = *sub_matches.get_one::<u64>("start").unwrap();
+        let count = *sub_matches.get_one::<u64>("count").unwrap();
+        slice(input_path, output_path, start, count)
+    } else if subcommand == "filter" {
+        let sub_matches = matches.subcommand_matches("filter").unwrap();
+        let input_path = Path::new(sub_matches.get_one::<String>("input").unwrap());
+        let output_path = Path::new(sub_matches.get_one::<String>("output").unwrap());
+        let conditions = Filter {
+            particles: sub_matches
+                .get_many::<String>("particle")
+                .map(|names| names.map(|name| name.parse().unwrap()).collect())
+                .unwrap_or_default(),
+            min_energy: sub_matches.get_one::<f32>("min-energy").copied(),
+            max_energy: sub_matches.get_one::<f32>("max-energy").copied(),
+            field: field_from(sub_matches),
+            max_angle: sub_matches.get_one::<f64>("max-angle").copied(),
+            direction: sub_matches
+                .get_one::<String>("direction")
+                .map(|direction| match direction.as_str() {
+                    "backward" => Direction::Backward,
+                    _ => Direction::Forward,
+                }),
+            regions: sub_matches
+                .get_many::<u32>("region")
+                .map(|regions| regions.copied().collect())
+                .unwrap_or_default(),
+            latch_set: *sub_matches.get_one::<u32>("latch-set").unwrap(),
+            latch_clear: *sub_matches.get_one::<u32>("latch-clear").unwrap(),
+            condition: sub_matches.get_one::<Expr>("where").cloned(),
+        };
+        filter(input_path, output_path, &conditions)
+    } else if subcommand == "convert" {
+        let sub_matches = matches.subcommand_matches("convert").unwrap();
+        let input_path = Path::new(sub_matches.get_one::<String>("input").unwrap());
+        let output_path = Path::new(sub_matches.get_one::<String>("output").unwrap());
+        if is_iaea_path(input_path) {
+            println!(
+                "convert IAEA {} to egsphsp {}",
+                input_path.display(),
+                output_path.display()
+            );
+            iaea_to_egsphsp(input_path, output_path)
+        } else {
+            let z = *sub_matches.get_one::<f32>("z").unwrap();
+            println!(
+                "convert egsphsp {} to IAEA {}",
+                input_path.display(),
+                output_path.display()
+            );
+            egsphsp_to_iaea(input_path, output_path, z)
+        }
+    } else if subcommand == "convert-mode" {
+        let sub_matches = matches.subcommand_matches("convert-mode").unwrap();
+        let input_path = Path::new(sub_matches.get_one::<String>("input").unwrap());
+        let output_path = if sub_matches.get_flag("in-place") {
+            input_path
+        } else {
+            Path::new(sub_matches.get_one::<String>("output").unwrap())
+        };
+        let using_zlast = sub_matches.get_one::<String>("mode").unwrap().ends_with('2');
+        let zlast = sub_matches.get_one::<f32>("zlast").copied();
+        convert_mode(input_path, output_path, using_zlast, zlast)
     } else if subcommand == "stats" {
         let sub_matches = matches.subcommand_matches("stats").unwrap();
         let path = Path::new(sub_matches.get_one::<String>("input").unwrap());
         let reader = PHSPReader::from(File::open(path).unwrap()).unwrap();
         let header = reader.header;
-        // let mut max_x = f32::MIN;
-        // let mut min_x = f32::MAX;
-        // let mut max_y = f32::MIN;
-        // let mut min_y = f32::MAX;
-        // for record in reader.map(|r| r.unwrap()) {
-        // max_x = max_x.max(record.x_cm);
-        // min_x = min_x.min(record.x_cm);
-        // max_y = max_y.max(record.y_cm);
-        // min_y = min_y.min(record.y_cm);
-        // }
+        let scanned = if sub_matches.get_flag("scan") {
+            match scan_reader(reader) {
+                Ok(stats) => Some(stats),
+                Err(err) => {
+                    println!("Error: {}", err);
+                    exit(1);
+                }
+            }
+        } else {
+            None
+        };
 
         if sub_matches.get_one::<String>("format").unwrap() == "json" {
-            // TODO use a proper serializer!
-            println!("{{");
-            println!("\t\"total_particles\": {},", header.total_particles);
-            println!("\t\"total_photons\": {},", header.total_photons);
-            println!("\t\"maximum_energy\": {},", header.max_energy);
-            println!("\t\"minimum_energy\": {},", header.min_energy);
-            println!(
-                "\t\"total_particles_in_source\": {}",
-                header.total_particles_in_source
-            );
-            println!("}}");
+            let summary = StatsSummary {
+                total_particles: header.total_particles,
+                total_photons: header.total_photons,
+                maximum_energy: header.max_energy,
+                minimum_energy: header.min_energy,
+                total_particles_in_source: header.total_particles_in_source,
+                scanned,
+            };
+            println!("{}", serde_json::to_string_pretty(&summary).unwrap());
         } else {
             println!("Total particles: {}", header.total_particles);
             println!("Total photons: {}", header.total_photons);
@@ -370,11 +1220,9 @@ fn main() {
                 "Incident particles from source: {:.*}",
                 1, header.total_particles_in_source
             );
-            // println!("X position in [{}, {}], Y position in [{}, {}]",
-            // min_x,
-            // max_x,
-            // min_y,
-            // max_y);
+            if let Some(stats) = scanned {
+                print_particle_stats(&stats);
+            }
         }
         Ok(())
     } else {
@@ -455,3 +1303,249 @@ fn main() {
         }
     };
 }
+
+// the arguments shared by every analysis: the input, particle types and output
+fn analysis_command(name: &'static str, default_particles: &'static str) -> Command {
+    Command::new(name)
+        .arg(Arg::new("input")
+            .help("Phase space files - each adds its own columns, labelled by file name")
+            .num_args(1..)
+            .required(true))
+        .arg(Arg::new("particle")
+            .short('p')
+            .long("particle")
+            .value_parser(["all", "photons", "electrons", "positrons", "charged"])
+            .default_value(default_particles)
+            .value_delimiter(',')
+            .help("Comma separated particle types to analyse, one column each"))
+        .arg(Arg::new("output")
+            .short('o')
+            .long("output")
+            .help("Write the table to this file instead of standard output"))
+        .arg(Arg::new("format")
+            .long("format")
+            .default_value("text")
+            .value_parser(["text", "xmgrace"])
+            .help("Plain text columns, or an xmgrace project with a set per column"))
+        .arg(Arg::new("plot")
+            .long("plot")
+            .value_name("SVG")
+            .help("Also draw the result as an SVG plot"))
+        .arg(Arg::new("plot-style")
+            .long("plot-style")
+            .default_value("step")
+            .value_parser(["step", "line"])
+            .help("Histogram steps across each bin, or lines between bin centers"))
+        .arg(Arg::new("log-y")
+            .long("log-y")
+            .action(clap::ArgAction::SetTrue)
+            .help("Plot with a log y axis"))
+}
+
+// BEAMDP style analysis of position in zones about the z axis
+fn profile_command(name: &'static str, default_particles: &'static str) -> Command {
+    analysis_command(name, default_particles)
+        .arg(Arg::new("zones")
+            .long("zones")
+            .default_value("annular")
+            .value_parser(["annular", "square"])
+            .help("Circular rings, or square rings binned on the larger of |x| and |y|"))
+        .arg(Arg::new("bins")
+            .long("bins")
+            .value_name("BINS")
+            .value_parser(value_parser!(usize))
+            .default_value("20"))
+        .arg(Arg::new("max-radius")
+            .short('r')
+            .long("max-radius")
+            .value_name("CM")
+            .value_parser(value_parser!(f64))
+            .required(true)
+            .help("Outer radius (or half width for square zones) of the last zone"))
+}
+
+// restricts an analysis to a circle or rectangle of the scoring plane
+fn field_args(command: Command) -> Command {
+    command
+        .arg(Arg::new("radius")
+            .long("radius")
+            .value_name("CM")
+            .value_parser(value_parser!(f32))
+            .conflicts_with("rectangle")
+            .help("Only count particles within this distance of the z axis"))
+        .arg(Arg::new("rectangle")
+            .long("rectangle")
+            .value_names(["X_MIN", "X_MAX", "Y_MIN", "Y_MAX"])
+            .value_parser(value_parser!(f32))
+            .num_args(4)
+            .allow_hyphen_values(true)
+            .help("Only count particles inside this rectangle"))
+}
+
+fn where_arg() -> Arg {
+    Arg::new("where")
+        .long("where")
+        .value_name("EXPRESSION")
+        .value_parser(|s: &str| s.parse::<Expr>())
+        .help("Only particles for which this expression is true, e.g. \"e > 1 && r < 5\"")
+}
+
+// latch masks are easier to write in hex
+fn parse_mask(value: &str) -> Result<u32, String> {
+    match value.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => value.parse(),
+    }
+    .map_err(|e| e.to_string())
+}
+
+fn field_from(sub_matches: &ArgMatches) -> Field {
+    if let Some(radius) = sub_matches.get_one::<f32>("radius") {
+        Field::Circle { radius: *radius }
+    } else if let Some(values) = sub_matches.get_many::<f32>("rectangle") {
+        let values: Vec<f32> = values.copied().collect();
+        Field::Rectangle {
+            x_min: values[0],
+            x_max: values[1],
+            y_min: values[2],
+            y_max: values[3],
+        }
+    } else {
+        Field::Everywhere
+    }
+}
+
+// bins from --min-energy and --max-energy, defaulting to the largest header maximum and
+// zero, or for log bins the lowest particle energy, since the header minimum only covers
+// charged particles
+fn energy_binning(sub_matches: &ArgMatches, paths: &[&Path], scale: Scale) -> EGSResult<Binning> {
+    let mut max_energy = f64::MIN;
+    for path in paths.iter() {
+        max_energy = max_energy.max(PHSPReader::open(path)?.header.max_energy as f64);
+    }
+    let min_energy = match sub_matches.get_one::<f64>("min-energy") {
+        Some(min_energy) => *min_energy,
+        None if scale == Scale::Log => {
+            let mut lowest = f64::MAX;
+            for path in paths.iter() {
+                if let Some(energy) = lowest_energy(PHSPReader::open(path)?)? {
+                    lowest = lowest.min(energy);
+                }
+            }
+            lowest
+        }
+        None => 0.0,
+    };
+    Binning::new(
+        min_energy,
+        sub_matches.get_one::<f64>("max-energy").copied().unwrap_or(max_energy),
+        *sub_matches.get_one::<usize>("bins").unwrap(),
+        scale,
+    )
+}
+
+fn zones_from(sub_matches: &ArgMatches) -> EGSResult<Zones> {
+    let shape = match sub_matches.get_one::<String>("zones").unwrap().as_str() {
+        "square" => ZoneShape::Square,
+        _ => ZoneShape::Annular,
+    };
+    Zones::new(
+        shape,
+        *sub_matches.get_one::<usize>("bins").unwrap(),
+        *sub_matches.get_one::<f64>("max-radius").unwrap(),
+    )
+}
+
+fn selections_from(sub_matches: &ArgMatches) -> Vec<Selection> {
+    sub_matches
+        .get_many::<String>("particle")
+        .unwrap()
+        .map(|name| name.parse().unwrap())
+        .collect()
+}
+
+// runs an analysis of every input file, merging the results into one distribution
+fn analyse_inputs<F>(sub_matches: &ArgMatches, analysis: F) -> EGSResult<Distribution>
+where
+    F: Fn(PHSPReader) -> EGSResult<Distribution>,
+{
+    let mut labelled = Vec::new();
+    for path in sub_matches.get_many::<String>("input").unwrap() {
+        let reader = PHSPReader::open(Path::new(path))?;
+        labelled.push((path.clone(), analysis(reader)?));
+    }
+    Distribution::merge(labelled)
+}
+
+fn write_distribution(distribution: &Distribution, sub_matches: &ArgMatches) -> EGSResult<()> {
+    if let Some(path) = sub_matches.get_one::<String>("plot") {
+        let options = PlotOptions {
+            style: match sub_matches.get_one::<String>("plot-style").unwrap().as_str() {
+                "line" => Style::Line,
+                _ => Style::Step,
+            },
+            log_x: distribution.log_x,
+            log_y: sub_matches.get_flag("log-y"),
+            ..PlotOptions::default()
+        };
+        write_svg(distribution, &options, File::create(path)?)?;
+    }
+    let output: Box<dyn io::Write> = match sub_matches.get_one::<String>("output") {
+        Some(path) => Box::new(File::create(path)?),
+        None => Box::new(io::stdout().lock()),
+    };
+    match sub_matches.get_one::<String>("format").unwrap().as_str() {
+        "xmgrace" => write_agr(distribution, output),
+        _ => write_table(distribution, output),
+    }
+}
+
+#[derive(Serialize)]
+struct StatsSummary {
+    total_particles: i32,
+    total_photons: i32,
+    maximum_energy: f32,
+    minimum_energy: f32,
+    total_particles_in_source: f32,
+    #[serde(flatten)]
+    scanned: Option<Stats>,
+}
+
+type StatsRow = (&'static str, fn(&ParticleStats) -> f64);
+
+fn print_particle_stats(stats: &Stats) {
+    let all = [&stats.photons, &stats.electrons, &stats.positrons];
+    println!();
+    println!(
+        "{:<28}{:>16}{:>16}{:>16}",
+        "", "Photons", "Electrons", "Positrons"
+    );
+    println!(
+        "{:<28}{:>16}{:>16}{:>16}",
+        "Count", all[0].count, all[1].count, all[2].count
+    );
+    let rows: [StatsRow; 13] = [
+        ("Summed weight", |p| p.total_weight),
+        ("Mean energy (MeV)", |p| p.mean_energy),
+        ("Energy weighted mean (MeV)", |p| p.energy_weighted_mean_energy),
+        ("Minimum x (cm)", |p| p.min_x as f64),
+        ("Maximum x (cm)", |p| p.max_x as f64),
+        ("Minimum y (cm)", |p| p.min_y as f64),
+        ("Maximum y (cm)", |p| p.max_y as f64),
+        ("RMS radius (cm)", |p| p.rms_radius),
+        ("Mean x direction cosine", |p| p.mean_x_cos),
+        ("Mean y direction cosine", |p| p.mean_y_cos),
+        ("Mean z direction cosine", |p| p.mean_z_cos),
+        ("Effective particles (Kish)", |p| p.effective_particles),
+        ("Backward fraction", |p| p.backward_fraction),
+    ];
+    for (name, value) in rows.iter() {
+        println!(
+            "{:<28}{:>16.6}{:>16.6}{:>16.6}",
+            name,
+            value(all[0]),
+            value(all[1]),
+            value(all[2])
+        );
+    }
+}
@@ -0,0 +1,96 @@
+use std::fs::File;
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::path::Path;
+
+use crate::{EGSError, EGSResult};
+
+/// A function of one variable given as points, such as a measured profile or spectrum,
+/// interpolated linearly between them.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Curve {
+    pub xs: Vec<f64>,
+    pub ys: Vec<f64>,
+}
+
+fn bad_curve(line: usize, message: String) -> EGSError {
+    EGSError::BadTable(format!("line {}: {}", line, message))
+}
+
+impl Curve {
+    /// Reads the first two columns (separated by whitespace or commas) of every line that
+    /// isn't blank or a `#` comment. The x values must increase.
+    pub fn read<R: Read>(input: R) -> EGSResult<Curve> {
+        let mut curve = Curve {
+            xs: Vec::new(),
+            ys: Vec::new(),
+        };
+        for (index, line) in BufReader::new(input).lines().enumerate() {
+            let line = line?;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut columns = line
+                .split(|c: char| c == ',' || c.is_whitespace())
+                .filter(|column| !column.is_empty());
+            let mut number = || -> EGSResult<f64> {
+                let column = columns
+                    .next()
+                    .ok_or_else(|| bad_curve(index + 1, "expected two columns".to_string()))?;
+                column
+                    .parse()
+                    .map_err(|_| bad_curve(index + 1, format!("invalid number {:?}", column)))
+            };
+            let (x, y) = (number()?, number()?);
+            if curve.xs.last().is_some_and(|last| x <= *last) {
+                return Err(bad_curve(index + 1, format!("{} does not increase", x)));
+            }
+            curve.xs.push(x);
+            curve.ys.push(y);
+        }
+        if curve.xs.is_empty() {
+            return Err(EGSError::BadTable("no points".to_string()));
+        }
+        Ok(curve)
+    }
+
+    pub fn open(path: &Path) -> EGSResult<Curve> {
+        Curve::read(File::open(path)?)
+    }
+
+    /// The value at `x`, holding the end values beyond the first and last points.
+    pub fn value(&self, x: f64) -> f64 {
+        let i = self.xs.partition_point(|&point| point < x);
+        if i == 0 {
+            self.ys[0]
+        } else if i == self.xs.len() {
+            self.ys[i - 1]
+        } else {
+            let t = (x - self.xs[i - 1]) / (self.xs[i] - self.xs[i - 1]);
+            self.ys[i - 1] + t * (self.ys[i] - self.ys[i - 1])
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_and_interpolates() {
+        let text = "# radius fluence\n0 1.0\n\n1, 3.0\n3\t2.0 ignored\n";
+        let curve = Curve::read(text.as_bytes()).unwrap();
+        assert_eq!(curve.xs, vec![0.0, 1.0, 3.0]);
+        assert_eq!(curve.value(-1.0), 1.0);
+        assert_eq!(curve.value(0.5), 2.0);
+        assert_eq!(curve.value(1.0), 3.0);
+        assert_eq!(curve.value(2.0), 2.5);
+        assert_eq!(curve.value(4.0), 2.0);
+        match Curve::read("0 1\n0 2\n".as_bytes()) {
+            Err(EGSError::BadTable(message)) => assert!(message.starts_with("line 2")),
+            other => panic!("expected BadTable, got {:?}", other),
+        }
+        assert!(Curve::read("0\n".as_bytes()).is_err());
+    }
+}
@@ -0,0 +1,649 @@
+use std::f64::consts::PI;
+use std::fmt;
+use std::str::FromStr;
+
+use crate::{Particle, Record};
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Variable {
+    /// Total energy in MeV.
+    Energy,
+    KineticEnergy,
+    X,
+    Y,
+    Radius,
+    U,
+    V,
+    /// Signed, so negative for particles going backward.
+    W,
+    /// Magnitude of the weight.
+    Weight,
+    /// NaN for MODE0 files.
+    Zlast,
+    Region,
+    /// -1 for electrons, 1 for positrons and 0 for photons.
+    Charge,
+    Latch,
+    /// 1 if first scored by the primary history (negative energy in the file).
+    Primary,
+}
+
+impl Variable {
+    fn from_name(name: &str) -> Option<Variable> {
+        Some(match name {
+            "e" => Variable::Energy,
+            "ke" => Variable::KineticEnergy,
+            "x" => Variable::X,
+            "y" => Variable::Y,
+            "r" => Variable::Radius,
+            "u" => Variable::U,
+            "v" => Variable::V,
+            "w" => Variable::W,
+            "weight" => Variable::Weight,
+            "zlast" => Variable::Zlast,
+            "region" => Variable::Region,
+            "charge" => Variable::Charge,
+            "latch" => Variable::Latch,
+            "primary" => Variable::Primary,
+            _ => return None,
+        })
+    }
+
+    fn value(&self, record: &Record) -> f64 {
+        match *self {
+            Variable::Energy => record.total_energy() as f64,
+            Variable::KineticEnergy => record.kinetic_energy() as f64,
+            Variable::X => record.x_cm as f64,
+            Variable::Y => record.y_cm as f64,
+            Variable::Radius => (record.x_cm as f64).hypot(record.y_cm as f64),
+            Variable::U => record.x_cos as f64,
+            Variable::V => record.y_cos as f64,
+            Variable::W => {
+                let w = record.z_cos() as f64;
+                if record.z_positive() {
+                    w
+                } else {
+                    -w
+                }
+            }
+            Variable::Weight => record.get_weight() as f64,
+            Variable::Zlast => record.zlast.map_or(f64::NAN, |z| z as f64),
+            Variable::Region => record.region_number() as f64,
+            Variable::Charge => match record.particle() {
+                Particle::Photon => 0.0,
+                Particle::Electron => -1.0,
+                Particle::Positron => 1.0,
+            },
+            Variable::Latch => record.latch as f64,
+            Variable::Primary => truth(record.first_scored_by_primary_history()),
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Function {
+    Abs,
+    Sqrt,
+    Exp,
+    Ln,
+    Log10,
+    Sin,
+    Cos,
+    Tan,
+    Asin,
+    Acos,
+    Atan,
+    Atan2,
+    Floor,
+    Ceil,
+    Min,
+    Max,
+    Pow,
+    /// `if(condition, then, else)`
+    If,
+    /// `bit(n)` is 1 if bit n of the latch is set.
+    Bit,
+}
+
+impl Function {
+    fn from_name(name: &str) -> Option<Function> {
+        Some(match name {
+            "abs" => Function::Abs,
+            "sqrt" => Function::Sqrt,
+            "exp" => Function::Exp,
+            "ln" | "log" => Function::Ln,
+            "log10" => Function::Log10,
+            "sin" => Function::Sin,
+            "cos" => Function::Cos,
+            "tan" => Function::Tan,
+            "asin" => Function::Asin,
+            "acos" => Function::Acos,
+            "atan" => Function::Atan,
+            "atan2" => Function::Atan2,
+            "floor" => Function::Floor,
+            "ceil" => Function::Ceil,
+            "min" => Function::Min,
+            "max" => Function::Max,
+            "pow" => Function::Pow,
+            "if" => Function::If,
+            "bit" => Function::Bit,
+            _ => return None,
+        })
+    }
+
+    fn arguments(&self) -> usize {
+        match *self {
+            Function::Atan2 | Function::Min | Function::Max | Function::Pow => 2,
+            Function::If => 3,
+            _ => 1,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Operator {
+    Or,
+    And,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+    Equal,
+    NotEqual,
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Remainder,
+    Power,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Node {
+    Number(f64),
+    Variable(Variable),
+    Negate(Box<Node>),
+    Not(Box<Node>),
+    Binary(Operator, Box<Node>, Box<Node>),
+    Call(Function, Vec<Node>),
+}
+
+fn truth(value: bool) -> f64 {
+    if value {
+        1.0
+    } else {
+        0.0
+    }
+}
+
+impl Node {
+    fn eval(&self, record: &Record) -> f64 {
+        match *self {
+            Node::Number(value) => value,
+            Node::Variable(variable) => variable.value(record),
+            Node::Negate(ref node) => -node.eval(record),
+            Node::Not(ref node) => {
+                let a = node.eval(record);
+                if a.is_nan() {
+                    a
+                } else {
+                    truth(a == 0.0)
+                }
+            }
+            Node::Binary(operator, ref left, ref right) => {
+                let a = left.eval(record);
+                // the boolean operators short circuit, but only on a known (not NaN) value
+                match operator {
+                    Operator::Or if a != 0.0 && !a.is_nan() => return 1.0,
+                    Operator::And if a == 0.0 => return 0.0,
+                    _ => (),
+                }
+                let b = right.eval(record);
+                match operator {
+                    Operator::Or if b != 0.0 && !b.is_nan() => 1.0,
+                    Operator::And if b == 0.0 => 0.0,
+                    // otherwise logic on an unknown value is unknown, like arithmetic
+                    Operator::Or
+                    | Operator::And
+                    | Operator::Less
+                    | Operator::LessEqual
+                    | Operator::Greater
+                    | Operator::GreaterEqual
+                    | Operator::Equal
+                    | Operator::NotEqual
+                        if a.is_nan() || b.is_nan() =>
+                    {
+                        f64::NAN
+                    }
+                    Operator::Or | Operator::And => truth(b != 0.0),
+                    Operator::Less => truth(a < b),
+                    Operator::LessEqual => truth(a <= b),
+                    Operator::Greater => truth(a > b),
+                    Operator::GreaterEqual => truth(a >= b),
+                    Operator::Equal => truth(a == b),
+                    Operator::NotEqual => truth(a != b),
+                    Operator::Add => a + b,
+                    Operator::Subtract => a - b,
+                    Operator::Multiply => a * b,
+                    Operator::Divide => a / b,
+                    Operator::Remainder => a % b,
+                    Operator::Power => a.powf(b),
+                }
+            }
+            Node::Call(Function::If, ref arguments) => {
+                let condition = arguments[0].eval(record);
+                if condition.is_nan() {
+                    condition
+                } else if condition != 0.0 {
+                    arguments[1].eval(record)
+                } else {
+                    arguments[2].eval(record)
+                }
+            }
+            Node::Call(function, ref arguments) => {
+                let a = arguments[0].eval(record);
+                let b = || arguments[1].eval(record);
+                match function {
+                    Function::Abs => a.abs(),
+                    Function::Sqrt => a.sqrt(),
+                    Function::Exp => a.exp(),
+                    Function::Ln => a.ln(),
+                    Function::Log10 => a.log10(),
+                    Function::Sin => a.sin(),
+                    Function::Cos => a.cos(),
+                    Function::Tan => a.tan(),
+                    Function::Asin => a.asin(),
+                    Function::Acos => a.acos(),
+                    Function::Atan => a.atan(),
+                    Function::Atan2 => a.atan2(b()),
+                    Function::Floor => a.floor(),
+                    Function::Ceil => a.ceil(),
+                    Function::Min => a.min(b()),
+                    Function::Max => a.max(b()),
+                    Function::Pow => a.powf(b()),
+                    Function::Bit => {
+                        truth((0.0..32.0).contains(&a) && record.latch & (1 << a as u32) != 0)
+                    }
+                    Function::If => unreachable!(),
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(f64),
+    Name(String),
+    Operator(&'static str),
+    Open,
+    Close,
+    Comma,
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Token::Number(value) => write!(f, "{}", value),
+            Token::Name(ref name) => write!(f, "{}", name),
+            Token::Operator(operator) => write!(f, "{}", operator),
+            Token::Open => write!(f, "("),
+            Token::Close => write!(f, ")"),
+            Token::Comma => write!(f, ","),
+        }
+    }
+}
+
+// longest first, so that <= is not read as < followed by =
+const OPERATORS: [&str; 17] = [
+    "||", "&&", "<=", ">=", "==", "!=", "<", ">", "!", "+", "-", "*", "/", "%", "^", "=", "|",
+];
+
+fn tokenize(text: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut rest = text.trim_start();
+    while let Some(c) = rest.chars().next() {
+        let length = if c.is_ascii_digit() || c == '.' {
+            // digits, a fraction and an exponent like 1.5e-3
+            let bytes = rest.as_bytes();
+            let mut end = 0;
+            while end < bytes.len() {
+                let b = bytes[end];
+                let exponent_sign = (b == b'+' || b == b'-')
+                    && end > 0
+                    && (bytes[end - 1] == b'e' || bytes[end - 1] == b'E');
+                if b.is_ascii_digit() || b == b'.' || b == b'e' || b == b'E' || exponent_sign {
+                    end += 1;
+                } else {
+                    break;
+                }
+            }
+            let number = &rest[..end];
+            tokens.push(Token::Number(
+                number
+                    .parse()
+                    .map_err(|_| format!("invalid number {:?}", number))?,
+            ));
+            end
+        } else if c.is_ascii_alphabetic() || c == '_' {
+            let end = rest
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                .unwrap_or(rest.len());
+            tokens.push(Token::Name(rest[..end].to_string()));
+            end
+        } else if c == '(' {
+            tokens.push(Token::Open);
+            1
+        } else if c == ')' {
+            tokens.push(Token::Close);
+            1
+        } else if c == ',' {
+            tokens.push(Token::Comma);
+            1
+        } else if let Some(operator) = OPERATORS.iter().find(|o| rest.starts_with(**o)) {
+            if *operator == "=" || *operator == "|" {
+                return Err(format!("unknown operator {:?}, did you mean {0}{0}?", operator));
+            }
+            tokens.push(Token::Operator(operator));
+            operator.len()
+        } else {
+            return Err(format!("unexpected character {:?}", c));
+        };
+        rest = rest[length..].trim_start();
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    position: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        token
+    }
+
+    // the operator at the current position, if it is one of `operators`, with the
+    // word forms of the boolean operators read as their symbols
+    fn operator(&self, operators: &[&'static str]) -> Option<&'static str> {
+        let symbol = match self.peek()? {
+            Token::Operator(operator) => *operator,
+            Token::Name(name) if name == "or" => "||",
+            Token::Name(name) if name == "and" => "&&",
+            Token::Name(name) if name == "not" => "!",
+            _ => return None,
+        };
+        operators.iter().find(|o| **o == symbol).copied()
+    }
+
+    fn expect(&mut self, expected: Token) -> Result<(), String> {
+        match self.next() {
+            Some(ref token) if *token == expected => Ok(()),
+            Some(token) => Err(format!("expected {} but found {}", expected, token)),
+            None => Err(format!("expected {} but the expression ended", expected)),
+        }
+    }
+
+    // one level of left associative binary operators
+    fn binary(
+        &mut self,
+        operators: &[&'static str],
+        operand: fn(&mut Parser) -> Result<Node, String>,
+    ) -> Result<Node, String> {
+        let mut node = operand(self)?;
+        while let Some(symbol) = self.operator(operators) {
+            self.position += 1;
+            let operator = match symbol {
+                "||" => Operator::Or,
+                "&&" => Operator::And,
+                "<" => Operator::Less,
+                "<=" => Operator::LessEqual,
+                ">" => Operator::Greater,
+                ">=" => Operator::GreaterEqual,
+                "==" => Operator::Equal,
+                "!=" => Operator::NotEqual,
+                "+" => Operator::Add,
+                "-" => Operator::Subtract,
+                "*" => Operator::Multiply,
+                "/" => Operator::Divide,
+                _ => Operator::Remainder,
+            };
+            node = Node::Binary(operator, Box::new(node), Box::new(operand(self)?));
+        }
+        Ok(node)
+    }
+
+    fn or(&mut self) -> Result<Node, String> {
+        self.binary(&["||"], Parser::and)
+    }
+
+    fn and(&mut self) -> Result<Node, String> {
+        self.binary(&["&&"], Parser::not)
+    }
+
+    fn not(&mut self) -> Result<Node, String> {
+        if self.operator(&["!"]).is_some() {
+            self.position += 1;
+            Ok(Node::Not(Box::new(self.not()?)))
+        } else {
+            self.comparison()
+        }
+    }
+
+    fn comparison(&mut self) -> Result<Node, String> {
+        self.binary(&["<", "<=", ">", ">=", "==", "!="], Parser::sum)
+    }
+
+    fn sum(&mut self) -> Result<Node, String> {
+        self.binary(&["+", "-"], Parser::product)
+    }
+
+    fn product(&mut self) -> Result<Node, String> {
+        self.binary(&["*", "/", "%"], Parser::negation)
+    }
+
+    fn negation(&mut self) -> Result<Node, String> {
+        if self.operator(&["-"]).is_some() {
+            self.position += 1;
+            Ok(Node::Negate(Box::new(self.negation()?)))
+        } else if self.operator(&["+"]).is_some() {
+            self.position += 1;
+            self.negation()
+        } else {
+            self.power()
+        }
+    }
+
+    fn power(&mut self) -> Result<Node, String> {
+        let base = self.primary()?;
+        if self.operator(&["^"]).is_some() {
+            self.position += 1;
+            // right associative, and binds tighter than a minus sign on its left only
+            let exponent = self.negation()?;
+            Ok(Node::Binary(Operator::Power, Box::new(base), Box::new(exponent)))
+        } else {
+            Ok(base)
+        }
+    }
+
+    fn primary(&mut self) -> Result<Node, String> {
+        match self.next() {
+            Some(Token::Number(value)) => Ok(Node::Number(value)),
+            Some(Token::Open) => {
+                let node = self.or()?;
+                self.expect(Token::Close)?;
+                Ok(node)
+            }
+            Some(Token::Name(name)) => {
+                if self.peek() == Some(&Token::Open) {
+                    let function = Function::from_name(&name)
+                        .ok_or_else(|| format!("unknown function {:?}", name))?;
+                    self.position += 1;
+                    let mut arguments = vec![self.or()?];
+                    while self.peek() == Some(&Token::Comma) {
+                        self.position += 1;
+                        arguments.push(self.or()?);
+                    }
+                    self.expect(Token::Close)?;
+                    if arguments.len() != function.arguments() {
+                        return Err(format!(
+                            "{} takes {} arguments but was given {}",
+                            name,
+                            function.arguments(),
+                            arguments.len()
+                        ));
+                    }
+                    Ok(Node::Call(function, arguments))
+                } else if name == "pi" {
+                    Ok(Node::Number(PI))
+                } else {
+                    Variable::from_name(&name)
+                        .map(Node::Variable)
+                        .ok_or_else(|| format!("unknown variable {:?}", name))
+                }
+            }
+            Some(token) => Err(format!("unexpected {}", token)),
+            None => Err("the expression ended early".to_string()),
+        }
+    }
+}
+
+/// A parsed expression over the quantities of a record, such as `e > 1 && r < 5` or
+/// `exp(-r/5)`, evaluated for one record at a time.
+///
+/// Everything is a number: comparisons and boolean operators give 1 for true and 0 for
+/// false, and any non-zero value is true. Operators from loosest to tightest binding are
+/// `||`, `&&`, `!`, comparisons (`<`, `<=`, `>`, `>=`, `==`, `!=`), `+` and `-`, `*`, `/`
+/// and `%`, unary minus, and `^` (power, right associative). `and`, `or` and `not` may be
+/// written instead of `&&`, `||` and `!`.
+///
+/// Variables are `e` (total energy in MeV), `ke` (kinetic energy), `x`, `y` and `r` (cm),
+/// the direction cosines `u`, `v` and `w` (negative going backward), `weight` (its
+/// magnitude), `zlast` (NaN for MODE0), `region` (latch bits 24-28), `charge` (-1, 0 or
+/// 1), `latch`, `primary` (first scored by the primary history) and the constant `pi`.
+/// Functions are `abs`, `sqrt`, `exp`, `ln` (or `log`), `log10`, `sin`, `cos`, `tan`,
+/// `asin`, `acos`, `atan`, `atan2`, `floor`, `ceil`, `min`, `max`, `pow`,
+/// `if(condition, then, else)` and `bit(n)`, which is 1 if bit n of the latch is set.
+///
+/// NaN stands for an unknown value, such as `zlast` in a MODE0 file. Comparisons and
+/// logic on it are NaN too, unless the other side of `&&` or `||` decides the result.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Expr {
+    text: String,
+    root: Node,
+}
+
+impl Expr {
+    pub fn eval(&self, record: &Record) -> f64 {
+        self.root.eval(record)
+    }
+
+    /// The expression as a function of radius alone, evaluated for a particle at (r, 0)
+    /// going along +z with unit weight and no energy.
+    pub fn eval_at_radius(&self, r: f64) -> f64 {
+        self.eval(&Record {
+            latch: 0,
+            total_energy: 0.0,
+            x_cm: r as f32,
+            y_cm: 0.0,
+            x_cos: 0.0,
+            y_cos: 0.0,
+            weight: 1.0,
+            zlast: None,
+        })
+    }
+
+    /// Whether the expression is true (non-zero) for the record. NaN is unknown, so it
+    /// doesn't match: a condition on `zlast` keeps no particles of a MODE0 file.
+    pub fn matches(&self, record: &Record) -> bool {
+        let value = self.eval(record);
+        value != 0.0 && !value.is_nan()
+    }
+}
+
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.text)
+    }
+}
+
+impl FromStr for Expr {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Expr, String> {
+        let mut parser = Parser {
+            tokens: tokenize(s)?,
+            position: 0,
+        };
+        let root = parser.or()?;
+        if let Some(token) = parser.peek() {
+            return Err(format!("unexpected {} after the end of the expression", token));
+        }
+        Ok(Expr {
+            text: s.trim().to_string(),
+            root,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::particle;
+
+    fn eval(text: &str, record: &Record) -> f64 {
+        text.parse::<Expr>().unwrap().eval(record)
+    }
+
+    #[test]
+    fn evaluates_with_precedence() {
+        let record = Record {
+            x_cos: 0.6,
+            ..particle((1 << 30) | (4 << 24), -2.0, 3.0, 4.0, -0.5)
+        };
+        assert_eq!(eval("1 + 2 * 3 ^ 2", &record), 19.0);
+        assert_eq!(eval("-2^2", &record), -4.0);
+        assert_eq!(eval("2^3^2", &record), 512.0);
+        assert_eq!(eval("(1 + 2) * 3 % 4", &record), 1.0);
+        assert_eq!(eval("r", &record), 5.0);
+        assert!((eval("w", &record) + 0.8).abs() < 1e-6);
+        assert_eq!(eval("weight + e + charge + region", &record), 5.5);
+        assert_eq!(eval("e > 1 && r <= 5 and not x == 4", &record), 1.0);
+        assert_eq!(eval("e > 3 || !primary", &record), 0.0);
+        assert_eq!(eval("bit(30) + bit(29) + if(latch > 0, 10, 20)", &record), 11.0);
+        assert_eq!(eval("max(x, y) * exp(0) + 1.5e1", &record), 19.0);
+        assert!(eval("zlast", &record).is_nan());
+    }
+
+    #[test]
+    fn unknown_values_do_not_match() {
+        let record = particle(0, 2.0, 3.0, 0.0, 1.0);
+        let matches = |text: &str| text.parse::<Expr>().unwrap().matches(&record);
+        for text in ["zlast", "!(zlast < 5)", "zlast != 5", "!zlast", "if(zlast, 1, 1)"] {
+            assert!(!matches(text), "{}", text);
+            assert!(!matches(&format!("x > 0 && {}", text)), "{}", text);
+        }
+        assert!(eval("zlast < 5", &record).is_nan());
+        assert!(matches("zlast < 5 || x > 0"));
+        assert!(!matches("zlast < 5 && x > 0"));
+        assert!(!matches("x < 0 && zlast"));
+        assert_eq!(eval("x < 0 && zlast", &record), 0.0);
+        assert_eq!(eval("zlast > 1 || x > 1", &record), 1.0);
+    }
+
+    #[test]
+    fn reports_errors() {
+        assert!("unknown + 1".parse::<Expr>().unwrap_err().contains("variable"));
+        assert!("sqrt(1, 2)".parse::<Expr>().unwrap_err().contains("takes 1"));
+        assert!("x = 1".parse::<Expr>().unwrap_err().contains("=="));
+        assert!("(x + 1".parse::<Expr>().unwrap_err().contains("expected )"));
+        assert!("x 1".parse::<Expr>().unwrap_err().contains("after the end"));
+        assert!("x +".parse::<Expr>().unwrap_err().contains("ended"));
+    }
+}
@@ -0,0 +1,190 @@
+use std::fs::{remove_file, rename, File, OpenOptions};
+use std::io::prelude::*;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::process;
+
+use crate::analysis::{polar_angle, Field, Selection};
+use crate::expr::Expr;
+use crate::{EGSResult, PHSPReader, PHSPTrackingWriter, Record};
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Direction {
+    Forward,
+    Backward,
+}
+
+/// Conditions a particle must meet to be kept. The default keeps everything.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Filter {
+    /// Keep particles matching any of these (all particles when empty).
+    pub particles: Vec<Selection>,
+    /// Total energy bounds in MeV, inclusive.
+    pub min_energy: Option<f32>,
+    pub max_energy: Option<f32>,
+    pub field: Field,
+    /// Largest angle from the +z axis in degrees, so backward particles are over 90.
+    pub max_angle: Option<f64>,
+    pub direction: Option<Direction>,
+    /// Keep particles from any of these regions (all regions when empty).
+    pub regions: Vec<u32>,
+    /// Latch bits that must all be set.
+    pub latch_set: u32,
+    /// Latch bits that must all be clear.
+    pub latch_clear: u32,
+    /// An expression that must be true, for conditions the fields above can't express.
+    pub condition: Option<Expr>,
+}
+
+impl Filter {
+    pub fn matches(&self, record: &Record) -> bool {
+        let energy = record.total_energy();
+        (self.particles.is_empty() || self.particles.iter().any(|p| p.matches(record)))
+            && self.min_energy.is_none_or(|min| energy >= min)
+            && self.max_energy.is_none_or(|max| energy <= max)
+            && self.field.contains(record.x_cm, record.y_cm)
+            && self.max_angle.is_none_or(|max| polar_angle(record) <= max)
+            && match self.direction {
+                Some(Direction::Forward) => record.z_positive(),
+                Some(Direction::Backward) => !record.z_positive(),
+                None => true,
+            }
+            && (self.regions.is_empty() || self.regions.contains(&record.region_number()))
+            && record.latch & self.latch_set == self.latch_set
+            && record.latch & self.latch_clear == 0
+            && self.condition.as_ref().is_none_or(|condition| condition.matches(record))
+    }
+}
+
+// a new file next to `path` for rewriting it in place, named so it can't clobber anything
+pub(crate) fn create_temporary(path: &Path, label: &str) -> EGSResult<(PathBuf, File)> {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let mut attempt = 0;
+    loop {
+        let temporary_path = path.with_file_name(format!(
+            ".{}.{}-{}-{}",
+            name,
+            label,
+            process::id(),
+            attempt
+        ));
+        match OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&temporary_path)
+        {
+            Ok(file) => return Ok((temporary_path, file)),
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => attempt += 1,
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+fn write_filtered<W: Write + Seek>(
+    reader: PHSPReader,
+    output: W,
+    filter: &Filter,
+) -> EGSResult<(u64, i32)> {
+    let header = reader.header;
+    let mut writer =
+        PHSPTrackingWriter::new(output, header.using_zlast, header.total_particles_in_source)?;
+    let mut read = 0;
+    for record in reader {
+        let record = record?;
+        read += 1;
+        if filter.matches(&record) {
+            writer.write(&record)?;
+        }
+    }
+    Ok((read, writer.finish()?.total_particles))
+}
+
+/// Writes the particles that pass `filter` to a new file. The header is recomputed from
+/// the kept particles, but keeps the number of particles in the source, since the kept
+/// particles still came from all of its histories.
+pub fn filter(input_path: &Path, output_path: &Path, filter: &Filter) -> EGSResult<()> {
+    let reader = PHSPReader::open(input_path)?;
+    let (read, kept) = if input_path == output_path {
+        // the output is usually shorter, so in place filtering goes through a temporary
+        // file, which is removed if anything goes wrong
+        let (temporary_path, file) = create_temporary(output_path, "filter")?;
+        let result = write_filtered(reader, file, filter).and_then(|counts| {
+            rename(&temporary_path, output_path)?;
+            Ok(counts)
+        });
+        if result.is_err() {
+            let _ = remove_file(&temporary_path);
+        }
+        result?
+    } else {
+        write_filtered(reader, File::create(output_path)?, filter)?
+    };
+    println!("Kept {} of {} particles", kept, read);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::particle;
+
+    #[test]
+    fn conditions_combine() {
+        let photon = Record {
+            x_cos: 0.1,
+            ..particle(3 << 24, 2.0, 1.0, 0.0, 1.0)
+        };
+        let electron = Record {
+            x_cos: 0.6,
+            ..particle(1 << 30, 1.5, 4.0, 0.0, -1.0)
+        };
+        let everything = Filter::default();
+        assert!(everything.matches(&photon) && everything.matches(&electron));
+
+        let electrons_above_1_mev = Filter {
+            particles: vec![Selection::Electrons],
+            min_energy: Some(1.0),
+            ..Filter::default()
+        };
+        assert!(!electrons_above_1_mev.matches(&photon));
+        assert!(electrons_above_1_mev.matches(&electron));
+
+        let in_field = Filter {
+            field: Field::Rectangle {
+                x_min: -2.0,
+                x_max: 2.0,
+                y_min: -2.0,
+                y_max: 2.0,
+            },
+            ..Filter::default()
+        };
+        assert!(in_field.matches(&photon) && !in_field.matches(&electron));
+
+        let forward_within_10 = Filter {
+            max_angle: Some(10.0),
+            direction: Some(Direction::Forward),
+            ..Filter::default()
+        };
+        assert!(forward_within_10.matches(&photon));
+        assert!(!forward_within_10.matches(&electron));
+
+        let region_3 = Filter {
+            regions: vec![3],
+            ..Filter::default()
+        };
+        assert!(region_3.matches(&photon) && !region_3.matches(&electron));
+
+        let latch = Filter {
+            latch_set: 1 << 30,
+            latch_clear: 1 << 24,
+            ..Filter::default()
+        };
+        assert!(!latch.matches(&photon) && latch.matches(&electron));
+
+        let condition = Filter {
+            condition: Some("x < 2 && e > 1.9".parse().unwrap()),
+            ..Filter::default()
+        };
+        assert!(condition.matches(&photon) && !condition.matches(&electron));
+    }
+}
@@ -0,0 +1,740 @@
+use std::fmt;
+use std::fs::File;
+use std::io::prelude::*;
+use std::io::{BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+
+use byteorder::{BigEndian, ByteOrder, LittleEndian};
+
+use crate::{
+    BUFFER_CAPACITY, EGSError, EGSResult, ELECTRON_REST_MASS, PHSPReader, PHSPTrackingWriter,
+    Particle, Record,
+};
+
+const HEADER_EXTENSION: &str = "IAEAheader";
+const PHSP_EXTENSION: &str = "IAEAphsp";
+
+// extra float and extra long type codes from the IAEA phase space report (INDC(NDS)-0484)
+const EXTRA_FLOAT_ZLAST: i32 = 3;
+const EXTRA_LONG_LATCH: i32 = 2;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Endian {
+    Little,
+    Big,
+}
+
+/// Which of the optional record fields are stored in the binary file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RecordContents {
+    pub x: bool,
+    pub y: bool,
+    pub z: bool,
+    pub u: bool,
+    pub v: bool,
+    pub w: bool,
+    pub weight: bool,
+    pub extra_floats: Vec<i32>,
+    pub extra_longs: Vec<i32>,
+}
+
+/// Values used in place of fields that are not stored in each record.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct RecordConstants {
+    pub x: f32,
+    pub y: f32,
+    pub z: f32,
+    pub u: f32,
+    pub v: f32,
+    pub w: f32,
+    pub weight: f32,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct IAEAHeader {
+    pub title: String,
+    pub file_type: i32,
+    pub contents: RecordContents,
+    pub constants: RecordConstants,
+    pub record_length: usize,
+    pub byte_order: Endian,
+    pub orig_histories: f64,
+    pub particles: u64,
+    pub photons: u64,
+    pub electrons: u64,
+    pub positrons: u64,
+}
+
+pub struct IAEAReader {
+    reader: BufReader<File>,
+    pub header: IAEAHeader,
+    next_record: u64,
+}
+
+pub struct IAEAWriter {
+    writer: BufWriter<File>,
+    header_path: PathBuf,
+    pub header: IAEAHeader,
+}
+
+/// Returns the (`.IAEAheader`, `.IAEAphsp`) pair for a path naming either file or their
+/// common stem.
+pub fn iaea_paths(path: &Path) -> (PathBuf, PathBuf) {
+    let stem = if is_iaea_path(path) {
+        path.with_extension("")
+    } else {
+        path.to_path_buf()
+    };
+    let mut header_path = stem.clone().into_os_string();
+    header_path.push(".");
+    header_path.push(HEADER_EXTENSION);
+    let mut phsp_path = stem.into_os_string();
+    phsp_path.push(".");
+    phsp_path.push(PHSP_EXTENSION);
+    (header_path.into(), phsp_path.into())
+}
+
+pub fn is_iaea_path(path: &Path) -> bool {
+    match path.extension().and_then(|e| e.to_str()) {
+        Some(ext) => {
+            ext.eq_ignore_ascii_case(HEADER_EXTENSION) || ext.eq_ignore_ascii_case(PHSP_EXTENSION)
+        }
+        None => false,
+    }
+}
+
+fn bad_header(message: &str) -> EGSError {
+    EGSError::BadIAEAHeader(message.to_string())
+}
+
+fn parse_value<T: std::str::FromStr>(line: Option<&&str>, what: &str) -> EGSResult<T> {
+    line.and_then(|l| l.split_whitespace().next())
+        .and_then(|token| token.parse::<T>().ok())
+        .ok_or_else(|| bad_header(&format!("missing or invalid {}", what)))
+}
+
+impl RecordContents {
+    fn stored_floats(&self) -> usize {
+        // w is never stored, it is recovered from u, v and the sign of the energy
+        [self.x, self.y, self.z, self.u, self.v, self.weight]
+            .iter()
+            .filter(|&&stored| stored)
+            .count()
+    }
+    pub fn record_length(&self) -> usize {
+        1 + 4 * (1 + self.stored_floats() + self.extra_floats.len() + self.extra_longs.len())
+    }
+}
+
+impl IAEAHeader {
+    pub fn parse(text: &str) -> EGSResult<IAEAHeader> {
+        let mut sections: Vec<(&str, Vec<&str>)> = Vec::new();
+        for raw in text.lines() {
+            let line = raw.trim();
+            if line.starts_with('$') && line.ends_with(':') {
+                sections.push((&line[1..line.len() - 1], Vec::new()));
+            } else if let Some((_, lines)) = sections.last_mut() {
+                let value = match line.find("//") {
+                    Some(i) => line[..i].trim(),
+                    None => line,
+                };
+                if !value.is_empty() {
+                    lines.push(value);
+                }
+            }
+        }
+        let section = |key: &str| -> Option<&Vec<&str>> {
+            sections.iter().find(|(k, _)| *k == key).map(|(_, v)| v)
+        };
+        let required = |key: &str| -> EGSResult<&Vec<&str>> {
+            section(key).ok_or_else(|| bad_header(&format!("missing ${} section", key)))
+        };
+        let count = |key: &str| -> EGSResult<u64> {
+            match section(key) {
+                Some(lines) => parse_value(lines.first(), key),
+                None => Ok(0),
+            }
+        };
+
+        let lines = required("RECORD_CONTENTS")?;
+        let flag = |i: usize, what: &str| -> EGSResult<bool> {
+            parse_value::<i32>(lines.get(i), what).map(|v| v != 0)
+        };
+        let n_floats: usize = parse_value(lines.get(7), "extra float count")?;
+        let n_longs: usize = parse_value(lines.get(8), "extra long count")?;
+        let mut extra_floats = Vec::with_capacity(n_floats);
+        for i in 0..n_floats {
+            extra_floats.push(parse_value(lines.get(9 + i), "extra float type")?);
+        }
+        let mut extra_longs = Vec::with_capacity(n_longs);
+        for i in 0..n_longs {
+            extra_longs.push(parse_value(lines.get(9 + n_floats + i), "extra long type")?);
+        }
+        let contents = RecordContents {
+            x: flag(0, "X flag")?,
+            y: flag(1, "Y flag")?,
+            z: flag(2, "Z flag")?,
+            u: flag(3, "U flag")?,
+            v: flag(4, "V flag")?,
+            w: flag(5, "W flag")?,
+            weight: flag(6, "weight flag")?,
+            extra_floats,
+            extra_longs,
+        };
+
+        // one constant per field that is not stored, in record order
+        let mut constants = RecordConstants {
+            x: 0.0,
+            y: 0.0,
+            z: 0.0,
+            u: 0.0,
+            v: 0.0,
+            w: 0.0,
+            weight: 1.0,
+        };
+        let empty = Vec::new();
+        let mut values = section("RECORD_CONSTANT").unwrap_or(&empty).iter();
+        for (stored, constant) in [
+            (contents.x, &mut constants.x),
+            (contents.y, &mut constants.y),
+            (contents.z, &mut constants.z),
+            (contents.u, &mut constants.u),
+            (contents.v, &mut constants.v),
+            (contents.w, &mut constants.w),
+            (contents.weight, &mut constants.weight),
+        ] {
+            if !stored {
+                *constant = parse_value(values.next(), "record constant")?;
+            }
+        }
+
+        let record_length = parse_value(required("RECORD_LENGTH")?.first(), "record length")?;
+        if record_length != contents.record_length() {
+            return Err(bad_header(&format!(
+                "record length {} does not match record contents ({} bytes)",
+                record_length,
+                contents.record_length()
+            )));
+        }
+        let byte_order =
+            match parse_value::<String>(required("BYTE_ORDER")?.first(), "byte order")?.as_str() {
+                "1234" => Endian::Little,
+                "4321" => Endian::Big,
+                other => return Err(bad_header(&format!("unsupported byte order {}", other))),
+            };
+        Ok(IAEAHeader {
+            title: section("TITLE").map(|l| l.join("\n")).unwrap_or_default(),
+            file_type: match section("FILE_TYPE") {
+                Some(lines) => parse_value(lines.first(), "file type")?,
+                None => 0,
+            },
+            contents,
+            constants,
+            record_length,
+            byte_order,
+            orig_histories: match section("ORIG_HISTORIES") {
+                Some(lines) => parse_value(lines.first(), "original histories")?,
+                None => 0.0,
+            },
+            particles: parse_value(required("PARTICLES")?.first(), "particle count")?,
+            photons: count("PHOTONS")?,
+            electrons: count("ELECTRONS")?,
+            positrons: count("POSITRONS")?,
+        })
+    }
+    pub fn using_zlast(&self) -> bool {
+        self.contents.extra_floats.contains(&EXTRA_FLOAT_ZLAST)
+    }
+}
+
+impl fmt::Display for IAEAHeader {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let flag = |stored: bool| if stored { 1 } else { 0 };
+        let c = &self.contents;
+        writeln!(f, "$IAEA_INDEX:\n0\n")?;
+        writeln!(f, "$TITLE:\n{}\n", self.title)?;
+        writeln!(f, "$FILE_TYPE:\n{}\n", self.file_type)?;
+        writeln!(
+            f,
+            "$CHECKSUM:\n{}\n",
+            self.particles * self.record_length as u64
+        )?;
+        writeln!(f, "$RECORD_CONTENTS:")?;
+        for (stored, name) in [
+            (c.x, "X"),
+            (c.y, "Y"),
+            (c.z, "Z"),
+            (c.u, "U"),
+            (c.v, "V"),
+            (c.w, "W"),
+            (c.weight, "Weight"),
+        ] {
+            writeln!(f, "    {}     // {} is stored ?", flag(stored), name)?;
+        }
+        writeln!(
+            f,
+            "    {}     // Extra floats stored ?",
+            c.extra_floats.len()
+        )?;
+        writeln!(f, "    {}     // Extra longs stored ?", c.extra_longs.len())?;
+        for (i, t) in c.extra_floats.iter().enumerate() {
+            writeln!(f, "    {}     // Type of extra float [{}]", t, i)?;
+        }
+        for (i, t) in c.extra_longs.iter().enumerate() {
+            writeln!(f, "    {}     // Type of extra long [{}]", t, i)?;
+        }
+        writeln!(f, "\n$RECORD_CONSTANT:")?;
+        let k = &self.constants;
+        for (stored, value, name) in [
+            (c.x, k.x, "X"),
+            (c.y, k.y, "Y"),
+            (c.z, k.z, "Z"),
+            (c.u, k.u, "U"),
+            (c.v, k.v, "V"),
+            (c.w, k.w, "W"),
+            (c.weight, k.weight, "Weight"),
+        ] {
+            if !stored {
+                writeln!(f, "    {:.4}     // Constant {}", value, name)?;
+            }
+        }
+        writeln!(f, "\n$RECORD_LENGTH:\n{}\n", self.record_length)?;
+        let order = match self.byte_order {
+            Endian::Little => "1234",
+            Endian::Big => "4321",
+        };
+        writeln!(f, "$BYTE_ORDER:\n{}\n", order)?;
+        writeln!(f, "$ORIG_HISTORIES:\n{}\n", self.orig_histories)?;
+        writeln!(f, "$PARTICLES:\n{}\n", self.particles)?;
+        writeln!(f, "$PHOTONS:\n{}\n", self.photons)?;
+        writeln!(f, "$ELECTRONS:\n{}\n", self.electrons)?;
+        writeln!(f, "$POSITRONS:\n{}", self.positrons)
+    }
+}
+
+impl IAEAReader {
+    pub fn open(path: &Path) -> EGSResult<IAEAReader> {
+        let (header_path, phsp_path) = iaea_paths(path);
+        let mut text = String::new();
+        File::open(header_path)?.read_to_string(&mut text)?;
+        let header = IAEAHeader::parse(&text)?;
+        let file = File::open(phsp_path)?;
+        let actual_size = (file.metadata()?).len();
+        let expected_size = header.particles * header.record_length as u64;
+        if actual_size != expected_size {
+            writeln!(
+                &mut std::io::stderr(),
+                "Expected {} bytes in file, not {}",
+                expected_size,
+                actual_size
+            )
+            .unwrap();
+        }
+        Ok(IAEAReader {
+            reader: BufReader::with_capacity(BUFFER_CAPACITY, file),
+            header,
+            next_record: 0,
+        })
+    }
+
+    fn read_f32(&self, buffer: &[u8]) -> f32 {
+        match self.header.byte_order {
+            Endian::Little => LittleEndian::read_f32(buffer),
+            Endian::Big => BigEndian::read_f32(buffer),
+        }
+    }
+
+    fn read_u32(&self, buffer: &[u8]) -> u32 {
+        match self.header.byte_order {
+            Endian::Little => LittleEndian::read_u32(buffer),
+            Endian::Big => BigEndian::read_u32(buffer),
+        }
+    }
+
+    fn decode(&self, buffer: &[u8]) -> EGSResult<Record> {
+        let contents = &self.header.contents;
+        let constants = &self.header.constants;
+        let particle_type = buffer[0] as i8;
+        let particle = match particle_type.unsigned_abs() {
+            1 => Particle::Photon,
+            2 => Particle::Electron,
+            3 => Particle::Positron,
+            _ => return Err(EGSError::UnsupportedParticle(particle_type)),
+        };
+        let mut offset = 1;
+        let mut next = |stored: bool, constant: f32| -> f32 {
+            if stored {
+                offset += 4;
+                self.read_f32(&buffer[offset - 4..offset])
+            } else {
+                constant
+            }
+        };
+        let energy = next(true, 0.0);
+        let x = next(contents.x, constants.x);
+        let y = next(contents.y, constants.y);
+        next(contents.z, constants.z);
+        let u = next(contents.u, constants.u);
+        let v = next(contents.v, constants.v);
+        let weight = next(contents.weight, constants.weight);
+        let mut zlast = None;
+        for &t in contents.extra_floats.iter() {
+            let value = next(true, 0.0);
+            if t == EXTRA_FLOAT_ZLAST {
+                zlast = Some(value);
+            }
+        }
+        let mut latch = 0;
+        for &t in contents.extra_longs.iter() {
+            let value = self.read_u32(&buffer[offset..offset + 4]);
+            offset += 4;
+            if t == EXTRA_LONG_LATCH {
+                latch = value;
+            }
+        }
+        // the IAEA particle type is authoritative for the EGS charge bits
+        latch &= !(0b11 << 29);
+        latch |= match particle {
+            Particle::Photon => 0,
+            Particle::Electron => 1 << 30,
+            Particle::Positron => 1 << 29,
+        };
+        let z_negative = if contents.w {
+            energy.is_sign_negative()
+        } else {
+            constants.w.is_sign_negative()
+        };
+        let mut total_energy = energy.abs();
+        if particle != Particle::Photon {
+            total_energy += ELECTRON_REST_MASS;
+        }
+        if particle_type < 0 {
+            total_energy = -total_energy;
+        }
+        Ok(Record {
+            latch,
+            total_energy,
+            x_cm: x,
+            y_cm: y,
+            x_cos: u,
+            y_cos: v,
+            weight: if z_negative {
+                -weight.abs()
+            } else {
+                weight.abs()
+            },
+            zlast,
+        })
+    }
+}
+
+impl Iterator for IAEAReader {
+    type Item = EGSResult<Record>;
+    fn next(&mut self) -> Option<EGSResult<Record>> {
+        if self.next_record >= self.header.particles {
+            return None;
+        }
+        let mut buffer = vec![0; self.header.record_length];
+        if let Err(err) = self.reader.read_exact(&mut buffer) {
+            return Some(Err(EGSError::Io(err)));
+        }
+        self.next_record += 1;
+        Some(self.decode(&buffer))
+    }
+}
+
+impl IAEAWriter {
+    /// Creates the `.IAEAphsp` file; the `.IAEAheader` is written by `finish` once the
+    /// particle counts are known. Every record is stored in the scoring plane at `z`.
+    pub fn create(
+        path: &Path,
+        z: f32,
+        using_zlast: bool,
+        orig_histories: f64,
+    ) -> EGSResult<IAEAWriter> {
+        let (header_path, phsp_path) = iaea_paths(path);
+        let contents = RecordContents {
+            x: true,
+            y: true,
+            z: false,
+            u: true,
+            v: true,
+            w: true,
+            weight: true,
+            extra_floats: if using_zlast {
+                vec![EXTRA_FLOAT_ZLAST]
+            } else {
+                Vec::new()
+            },
+            extra_longs: vec![EXTRA_LONG_LATCH],
+        };
+        let header = IAEAHeader {
+            title: String::new(),
+            file_type: 0,
+            record_length: contents.record_length(),
+            contents,
+            constants: RecordConstants {
+                x: 0.0,
+                y: 0.0,
+                z,
+                u: 0.0,
+                v: 0.0,
+                w: 0.0,
+                weight: 1.0,
+            },
+            byte_order: Endian::Little,
+            orig_histories,
+            particles: 0,
+            photons: 0,
+            electrons: 0,
+            positrons: 0,
+        };
+        Ok(IAEAWriter {
+            writer: BufWriter::with_capacity(BUFFER_CAPACITY, File::create(phsp_path)?),
+            header_path,
+            header,
+        })
+    }
+
+    pub fn write(&mut self, record: &Record) -> EGSResult<()> {
+        let particle = record.particle();
+        let particle_type: i8 = match particle {
+            Particle::Photon => 1,
+            Particle::Electron => 2,
+            Particle::Positron => 3,
+        };
+        let mut buffer = [0; 1 + 4 * 8];
+        buffer[0] = if record.first_scored_by_primary_history() {
+            (-particle_type) as u8
+        } else {
+            particle_type as u8
+        };
+        let energy = record.kinetic_energy();
+        let values = [
+            if record.z_positive() { energy } else { -energy },
+            record.x_cm,
+            record.y_cm,
+            record.x_cos,
+            record.y_cos,
+            record.get_weight(),
+        ];
+        let mut offset = 1;
+        for value in values.iter() {
+            LittleEndian::write_f32(&mut buffer[offset..offset + 4], *value);
+            offset += 4;
+        }
+        if !self.header.contents.extra_floats.is_empty() {
+            LittleEndian::write_f32(
+                &mut buffer[offset..offset + 4],
+                record.zlast.expect("MODE2 record missing zlast"),
+            );
+            offset += 4;
+        }
+        LittleEndian::write_u32(&mut buffer[offset..offset + 4], record.latch);
+        offset += 4;
+        self.writer.write_all(&buffer[..offset])?;
+        self.header.particles += 1;
+        match particle {
+            Particle::Photon => self.header.photons += 1,
+            Particle::Electron => self.header.electrons += 1,
+            Particle::Positron => self.header.positrons += 1,
+        }
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> EGSResult<()> {
+        self.writer.flush()?;
+        let mut file = File::create(&self.header_path)?;
+        write!(file, "{}", self.header)?;
+        Ok(())
+    }
+}
+
+pub fn egsphsp_to_iaea(input_path: &Path, output_path: &Path, z: f32) -> EGSResult<()> {
+    let reader = PHSPReader::open(input_path)?;
+    let mut writer = IAEAWriter::create(
+        output_path,
+        z,
+        reader.header.using_zlast,
+        reader.header.total_particles_in_source as f64,
+    )?;
+    writer.header.title = format!("Converted by beamdpr from {}", input_path.display());
+    for record in reader {
+        writer.write(&record?)?;
+    }
+    println!("Converted {} particles", writer.header.particles);
+    writer.finish()
+}
+
+pub fn iaea_to_egsphsp(input_path: &Path, output_path: &Path) -> EGSResult<()> {
+    let reader = IAEAReader::open(input_path)?;
+    let mut writer = PHSPTrackingWriter::create(
+        output_path,
+        reader.header.using_zlast(),
+        reader.header.orig_histories as f32,
+    )?;
+    let mut skipped = 0;
+    for record in reader {
+        match record {
+            Ok(record) => writer.write(&record)?,
+            Err(EGSError::UnsupportedParticle(_)) => skipped += 1,
+            Err(err) => return Err(err),
+        }
+    }
+    if skipped > 0 {
+        println!("Skipped {} particles that egsphsp cannot represent", skipped);
+    }
+    let header = writer.finish()?;
+    println!("Converted {} particles", header.total_particles);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::tmp_path;
+
+    const HEADER: &str = "$IAEA_INDEX:
+   0
+
+$TITLE:
+Test beam
+
+$FILE_TYPE:
+   0
+
+$CHECKSUM:
+   58
+
+$RECORD_CONTENTS:
+    1     // X is stored ?
+    1     // Y is stored ?
+    0     // Z is stored ?
+    1     // U is stored ?
+    1     // V is stored ?
+    1     // W is stored ?
+    0     // Weight is stored ?
+    1     // Extra floats stored ?
+    1     // Extra longs stored ?
+    3     // Generic float variable stored in the extrafloat array [ 0]
+    1     // Incremental history number stored in the extralong array [ 0]
+
+$RECORD_CONSTANT:
+  100.0000     // Constant Z
+    2.0000     // Constant Weight
+
+$RECORD_LENGTH:
+   29
+
+$BYTE_ORDER:
+   4321
+
+$ORIG_HISTORIES:
+   1000
+
+$PARTICLES:
+   2
+
+$PHOTONS:
+   1
+
+$ELECTRONS:
+   1
+";
+
+    #[test]
+    fn parses_header_constants_and_extras() {
+        let header = IAEAHeader::parse(HEADER).unwrap();
+        assert_eq!(header.title, "Test beam");
+        assert!(header.contents.x && !header.contents.z && !header.contents.weight);
+        assert_eq!(header.contents.extra_floats, vec![3]);
+        assert_eq!(header.contents.extra_longs, vec![1]);
+        assert_eq!(header.constants.z, 100.0);
+        assert_eq!(header.constants.weight, 2.0);
+        assert_eq!(header.record_length, 29);
+        assert_eq!(header.byte_order, Endian::Big);
+        assert_eq!(header.orig_histories, 1000.0);
+        assert_eq!(header.particles, 2);
+        assert_eq!(header.positrons, 0);
+        assert!(header.using_zlast());
+    }
+
+    #[test]
+    fn rejects_inconsistent_record_length() {
+        let text = HEADER.replace("   29\n", "   33\n");
+        assert!(matches!(
+            IAEAHeader::parse(&text),
+            Err(EGSError::BadIAEAHeader(_))
+        ));
+    }
+
+    #[test]
+    fn written_header_parses_back() {
+        let path = tmp_path("iaea");
+        let writer = IAEAWriter::create(&path, 50.0, true, 10.0).unwrap();
+        let expected = writer.header.clone();
+        writer.finish().unwrap();
+        let (header_path, phsp_path) = iaea_paths(&path);
+        let text = std::fs::read_to_string(&header_path).unwrap();
+        let _ = std::fs::remove_file(&header_path);
+        let _ = std::fs::remove_file(&phsp_path);
+        assert_eq!(IAEAHeader::parse(&text).unwrap(), expected);
+    }
+
+    #[test]
+    fn decodes_big_endian_record() {
+        let path = tmp_path("iaea_be");
+        let (header_path, phsp_path) = iaea_paths(&path);
+        std::fs::write(&header_path, HEADER).unwrap();
+        let mut data = Vec::new();
+        for (t, e, x) in [(-2i8, -1.5f32, 3.0f32), (1, 0.25, -1.0)] {
+            let mut record = [0; 29];
+            record[0] = t as u8;
+            let floats = [e, x, 0.5, 0.6, 0.0, 42.0];
+            for (i, value) in floats.iter().enumerate() {
+                BigEndian::write_f32(&mut record[1 + 4 * i..5 + 4 * i], *value);
+            }
+            BigEndian::write_u32(&mut record[25..29], 7);
+            data.extend_from_slice(&record);
+        }
+        std::fs::write(&phsp_path, &data).unwrap();
+        let records: Vec<Record> = IAEAReader::open(&phsp_path)
+            .unwrap()
+            .map(|r| r.unwrap())
+            .collect();
+        let _ = std::fs::remove_file(&header_path);
+        let _ = std::fs::remove_file(&phsp_path);
+
+        let electron = &records[0];
+        assert_eq!(electron.particle(), Particle::Electron);
+        assert!(electron.first_scored_by_primary_history());
+        assert!(!electron.z_positive());
+        assert!((electron.total_energy() - (1.5 + ELECTRON_REST_MASS)).abs() < 1e-6);
+        assert_eq!(electron.get_weight(), 2.0);
+        assert_eq!(electron.zlast, Some(42.0));
+        assert_eq!(electron.x_cm, 3.0);
+        assert_eq!(electron.y_cm, 0.5);
+        assert_eq!(electron.x_cos, 0.6);
+
+        let photon = &records[1];
+        assert_eq!(photon.particle(), Particle::Photon);
+        assert!(!photon.first_scored_by_primary_history());
+        assert!(photon.z_positive());
+        assert_eq!(photon.total_energy(), 0.25);
+    }
+
+    #[test]
+    fn rejects_unknown_particle_type() {
+        let path = tmp_path("iaea_type");
+        let (header_path, phsp_path) = iaea_paths(&path);
+        std::fs::write(&header_path, HEADER).unwrap();
+        // -128 has no positive counterpart as an i8
+        let mut record = [0; 29];
+        record[0] = 0x80;
+        std::fs::write(&phsp_path, record).unwrap();
+        let result = IAEAReader::open(&phsp_path).unwrap().next().unwrap();
+        let _ = std::fs::remove_file(&header_path);
+        let _ = std::fs::remove_file(&phsp_path);
+        assert!(matches!(result, Err(EGSError::UnsupportedParticle(-128))));
+    }
+}
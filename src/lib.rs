@@ -1,19 +1,42 @@
 use std::fmt;
-use std::fs::{remove_file, File, OpenOptions};
+use std::fs::{remove_file, rename, File, OpenOptions};
 use std::io;
 use std::io::prelude::*;
 use std::io::{BufReader, BufWriter};
+use std::ops::Range;
 use std::path::Path;
 
 use byteorder::{ByteOrder, LittleEndian};
 use float_cmp::ApproxEqUlps;
 use rand::{RngExt, SeedableRng, rngs::StdRng, seq::SliceRandom};
 
+use crate::analysis::{Binning, Selection, ZoneShape, Zones};
+
+pub mod analysis;
+pub mod curve;
+pub mod expr;
+pub mod filter;
+pub mod iaea;
+pub mod map;
+pub mod npy;
+pub mod plot;
+pub mod stats;
+pub mod table;
+pub mod validate;
+pub mod xmgrace;
+
+#[cfg(test)]
+mod testing;
+
 const HEADER_LENGTH: usize = 25;
 const MAX_RECORD_LENGTH: usize = 32;
 const BUFFER_CAPACITY: usize = 1024 * 1024;
 const MODE_LENGTH: usize = 5;
 const BATCHES: usize = 128; // too high and one hits ulimit (around 1024)
+const ELECTRON_REST_MASS: f32 = 0.511; // MeV, as used by BEAMnrc for header energies
+
+/// ZLAST written when converting MODE0 to MODE2 without a known value.
+pub const ZLAST_UNKNOWN: f32 = f32::NAN;
 
 #[derive(Debug, Copy, Clone)]
 pub struct Header {
@@ -39,6 +62,13 @@ pub struct Record {
     pub zlast: Option<f32>,
 }
 
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Particle {
+    Photon,
+    Electron,
+    Positron,
+}
+
 #[derive(Debug)]
 pub struct Transform;
 
@@ -50,6 +80,17 @@ pub enum EGSError {
     ModeMismatch,
     HeaderMismatch,
     RecordMismatch,
+    BadIAEAHeader(String),
+    UnsupportedParticle(i8),
+    Invalid(usize),
+    BadTable(String),
+    BadBinning(String),
+    MissingZlast,
+    BadWeightFactor(usize, f32),
+    BadTarget(String),
+    BadMap(String),
+    TooManyParticles(u64),
+    UnusedZlast,
 }
 
 pub type EGSResult<T> = Result<T, EGSError>;
@@ -79,27 +120,81 @@ impl fmt::Display for EGSError {
             EGSError::ModeMismatch => write!(f, "Input file MODE0/MODE2 do not match"),
             EGSError::HeaderMismatch => write!(f, "Headers are different"),
             EGSError::RecordMismatch => write!(f, "Records are different"),
+            EGSError::BadIAEAHeader(ref message) => write!(f, "Invalid IAEA header: {}", message),
+            EGSError::UnsupportedParticle(t) => {
+                write!(f, "IAEA particle type {} has no egsphsp equivalent", t)
+            }
+            EGSError::Invalid(problems) => write!(f, "Found {} problems", problems),
+            EGSError::BadTable(ref message) => write!(f, "Invalid table: {}", message),
+            EGSError::BadBinning(ref message) => write!(f, "Invalid binning: {}", message),
+            EGSError::MissingZlast => write!(f, "File is MODE0 and has no ZLAST"),
+            EGSError::BadWeightFactor(index, factor) => write!(
+                f,
+                "Weight factor {} for record {} is negative or not finite",
+                factor, index
+            ),
+            EGSError::BadTarget(ref message) => write!(f, "Invalid target: {}", message),
+            EGSError::BadMap(ref message) => write!(f, "Invalid map: {}", message),
+            EGSError::TooManyParticles(count) => {
+                write!(f, "{} particles are too many for the header to count", count)
+            }
+            EGSError::UnusedZlast => {
+                write!(f, "ZLAST can only be given when converting MODE0 to MODE2")
+            }
         }
     }
 }
 
-pub struct PHSPReader {
-    reader: BufReader<File>,
+pub struct PHSPReader<R: Read = File> {
+    reader: BufReader<R>,
     pub header: Header,
     next_record: u64,
 }
 
-pub struct PHSPWriter {
-    writer: BufWriter<File>,
+pub struct PHSPWriter<W: Write = File> {
+    writer: BufWriter<W>,
     pub header: Header,
 }
 
-impl PHSPReader {
-    pub fn from(file: File) -> EGSResult<PHSPReader> {
+/// Header totals accumulated record by record, filled in the way BEAMnrc does: the
+/// maximum energy is the largest kinetic energy of any particle and the minimum energy
+/// is the smallest kinetic energy of a charged particle (of any particle if none are
+/// charged).
+#[derive(Debug, Copy, Clone, Default)]
+pub struct HeaderStats {
+    pub total_particles: i32,
+    pub total_photons: i32,
+    pub max_energy: Option<f32>,
+    pub min_energy: Option<f32>,
+    pub min_charged_energy: Option<f32>,
+}
+
+/// A writer that keeps the header in step with the records written and rewrites it when
+/// finished (or dropped), so the output is always consistent.
+pub struct PHSPTrackingWriter<W: Write + Seek = File> {
+    writer: PHSPWriter<W>,
+    pub stats: HeaderStats,
+    total_particles_in_source: f32,
+    finished: bool,
+}
+
+impl PHSPReader<File> {
+    pub fn from(file: File) -> EGSResult<PHSPReader<File>> {
         let actual_size = (file.metadata()?).len();
-        let mut reader = BufReader::with_capacity(BUFFER_CAPACITY, file);
-        let mut buffer = [0; HEADER_LENGTH];
-        reader.read_exact(&mut buffer)?;
+        PHSPReader::new(file, Some(actual_size))
+    }
+    pub fn open(path: &Path) -> EGSResult<PHSPReader<File>> {
+        PHSPReader::from(File::open(path)?)
+    }
+}
+
+impl<R: Read> PHSPReader<R> {
+    /// Reads the header from any source. The byte length of the source is only checked
+    /// against the header when `length` is known.
+    pub fn new(inner: R, length: Option<u64>) -> EGSResult<PHSPReader<R>> {
+        let mut reader = BufReader::with_capacity(BUFFER_CAPACITY, inner);
+        let mut buffer = [0; MAX_RECORD_LENGTH];
+        reader.read_exact(&mut buffer[..HEADER_LENGTH])?;
         let mut mode = [0; MODE_LENGTH];
         mode.clone_from_slice(&buffer[0..5]);
         let header = Header {
@@ -118,7 +213,9 @@ impl PHSPReader {
                 return Err(EGSError::BadMode);
             },
         };
-        if actual_size != header.expected_size() as u64 {
+        if let Some(actual_size) = length
+            && actual_size != header.expected_size() as u64
+        {
             writeln!(
                 &mut std::io::stderr(),
                 "Expected {} bytes in file, not {}",
@@ -128,7 +225,8 @@ impl PHSPReader {
             .unwrap();
             //return Err(EGSError::BadLength);
         }
-        reader.consume(header.record_size as usize - HEADER_LENGTH);
+        // the header is padded out to the length of a record
+        reader.read_exact(&mut buffer[HEADER_LENGTH..header.record_size as usize])?;
         Ok(PHSPReader {
             reader,
             header,
@@ -138,9 +236,48 @@ impl PHSPReader {
     fn exhausted(&self) -> bool {
         self.next_record >= self.header.total_particles as u64
     }
+    pub fn len(&self) -> u64 {
+        self.header.total_particles as u64
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<R: Read + Seek> PHSPReader<R> {
+    /// Positions the reader so that the next record returned is record `n` (zero based).
+    pub fn seek_record(&mut self, n: u64) -> EGSResult<()> {
+        if n > self.len() {
+            return Err(EGSError::Io(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("record {} is past the end ({} records)", n, self.len()),
+            )));
+        }
+        let offset = (n + 1) * self.header.record_size;
+        self.reader.seek(io::SeekFrom::Start(offset))?;
+        self.next_record = n;
+        Ok(())
+    }
+    pub fn get(&mut self, n: u64) -> EGSResult<Record> {
+        if n >= self.len() {
+            return Err(EGSError::Io(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("record {} is out of range ({} records)", n, self.len()),
+            )));
+        }
+        self.seek_record(n)?;
+        self.next().expect("record within bounds")
+    }
+    /// Iterates over the records in `range`, clamped to the number of records.
+    pub fn range(&mut self, range: Range<u64>) -> EGSResult<std::iter::Take<&mut Self>> {
+        let start = range.start.min(self.len());
+        let end = range.end.clamp(start, self.len());
+        self.seek_record(start)?;
+        Ok(self.by_ref().take((end - start) as usize))
+    }
 }
 
-impl Iterator for PHSPReader {
+impl<R: Read> Iterator for PHSPReader<R> {
     type Item = EGSResult<Record>;
     fn next(&mut self) -> Option<EGSResult<Record>> {
         if self.next_record >= self.header.total_particles as u64 {
@@ -172,17 +309,19 @@ impl Iterator for PHSPReader {
     }
 }
 
-impl PHSPWriter {
-    pub fn from(file: File, header: &Header) -> EGSResult<PHSPWriter> {
-        let mut writer = BufWriter::with_capacity(BUFFER_CAPACITY, file);
-        let mut buffer = [0; MAX_RECORD_LENGTH];
-        buffer[0..5].clone_from_slice(&header.mode);
-        LittleEndian::write_i32(&mut buffer[5..9], header.total_particles);
-        LittleEndian::write_i32(&mut buffer[9..13], header.total_photons);
-        LittleEndian::write_f32(&mut buffer[13..17], header.max_energy);
-        LittleEndian::write_f32(&mut buffer[17..21], header.min_energy);
-        LittleEndian::write_f32(&mut buffer[21..25], header.total_particles_in_source);
-        writer.write_all(&buffer[..header.record_size as usize])?;
+impl PHSPWriter<File> {
+    pub fn from(file: File, header: &Header) -> EGSResult<PHSPWriter<File>> {
+        PHSPWriter::new(file, header)
+    }
+    pub fn create(path: &Path, header: &Header) -> EGSResult<PHSPWriter<File>> {
+        PHSPWriter::new(File::create(path)?, header)
+    }
+}
+
+impl<W: Write> PHSPWriter<W> {
+    pub fn new(inner: W, header: &Header) -> EGSResult<PHSPWriter<W>> {
+        let mut writer = BufWriter::with_capacity(BUFFER_CAPACITY, inner);
+        writer.write_all(&header.encode()[..header.record_size as usize])?;
         Ok(PHSPWriter {
             header: *header,
             writer,
@@ -208,9 +347,154 @@ impl PHSPWriter {
             .write_all(&buffer[..self.header.record_size as usize])?;
         Ok(())
     }
+
+    pub fn into_inner(self) -> EGSResult<W> {
+        self.writer
+            .into_inner()
+            .map_err(|err| EGSError::Io(err.into_error()))
+    }
+}
+
+impl<W: Write + Seek> PHSPWriter<W> {
+    /// Overwrites the header at the start of the output, leaving the position at the end
+    /// so records can continue to be written.
+    pub fn write_header(&mut self, header: &Header) -> EGSResult<()> {
+        if header.mode != self.header.mode {
+            return Err(EGSError::ModeMismatch);
+        }
+        self.writer.seek(io::SeekFrom::Start(0))?;
+        self.writer.write_all(&header.encode()[..HEADER_LENGTH])?;
+        self.writer.seek(io::SeekFrom::End(0))?;
+        self.header = *header;
+        Ok(())
+    }
+}
+
+impl PHSPTrackingWriter<File> {
+    pub fn create(
+        path: &Path,
+        using_zlast: bool,
+        total_particles_in_source: f32,
+    ) -> EGSResult<PHSPTrackingWriter<File>> {
+        PHSPTrackingWriter::new(File::create(path)?, using_zlast, total_particles_in_source)
+    }
+}
+
+impl<W: Write + Seek> PHSPTrackingWriter<W> {
+    pub fn new(
+        inner: W,
+        using_zlast: bool,
+        total_particles_in_source: f32,
+    ) -> EGSResult<PHSPTrackingWriter<W>> {
+        let mut header = Header::empty(using_zlast);
+        header.total_particles_in_source = total_particles_in_source;
+        Ok(PHSPTrackingWriter {
+            writer: PHSPWriter::new(inner, &header)?,
+            stats: HeaderStats::default(),
+            total_particles_in_source,
+            finished: false,
+        })
+    }
+
+    pub fn write(&mut self, record: &Record) -> EGSResult<()> {
+        self.stats.add(record)?;
+        self.writer.write(record)
+    }
+
+    pub fn set_total_particles_in_source(&mut self, total_particles_in_source: f32) {
+        self.total_particles_in_source = total_particles_in_source;
+    }
+
+    /// The header as it will be written by `finish`.
+    pub fn header(&self) -> Header {
+        let mut header = self.writer.header;
+        self.stats.apply(&mut header);
+        header.total_particles_in_source = self.total_particles_in_source;
+        header
+    }
+
+    fn finalize(&mut self) -> EGSResult<()> {
+        let header = self.header();
+        self.writer.write_header(&header)?;
+        self.writer.writer.flush()?;
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> EGSResult<Header> {
+        self.finished = true;
+        self.finalize()?;
+        Ok(self.header())
+    }
+}
+
+impl<W: Write + Seek> Drop for PHSPTrackingWriter<W> {
+    fn drop(&mut self) {
+        if !self.finished {
+            let _ = self.finalize();
+        }
+    }
+}
+
+impl HeaderStats {
+    /// Counts `record`, or fails if the header can't count any more particles.
+    pub fn add(&mut self, record: &Record) -> EGSResult<()> {
+        self.total_particles = self
+            .total_particles
+            .checked_add(1)
+            .ok_or(EGSError::TooManyParticles(i32::MAX as u64 + 1))?;
+        let energy = record.kinetic_energy();
+        if record.charged() {
+            self.min_charged_energy = Some(self.min_charged_energy.map_or(energy, |e| e.min(energy)));
+        } else {
+            self.total_photons += 1;
+        }
+        self.min_energy = Some(self.min_energy.map_or(energy, |e| e.min(energy)));
+        self.max_energy = Some(self.max_energy.map_or(energy, |e| e.max(energy)));
+        Ok(())
+    }
+    /// Overwrites the particle counts and energy bounds of `header`.
+    pub fn apply(&self, header: &mut Header) {
+        header.total_particles = self.total_particles;
+        header.total_photons = self.total_photons;
+        header.max_energy = self.max_energy.unwrap_or(0.0);
+        header.min_energy = self
+            .min_charged_energy
+            .or(self.min_energy)
+            .unwrap_or(0.0);
+    }
 }
 
 impl Header {
+    fn empty(using_zlast: bool) -> Header {
+        let mut header = Header {
+            mode: *b"MODE0",
+            total_particles: 0,
+            total_photons: 0,
+            min_energy: 0.0,
+            max_energy: 0.0,
+            total_particles_in_source: 0.0,
+            record_size: 28,
+            using_zlast: false,
+        };
+        header.set_using_zlast(using_zlast);
+        header
+    }
+    /// Switches between MODE0 and MODE2, keeping the mode bytes and record size in step.
+    pub fn set_using_zlast(&mut self, using_zlast: bool) {
+        self.using_zlast = using_zlast;
+        self.mode = if using_zlast { *b"MODE2" } else { *b"MODE0" };
+        self.record_size = if using_zlast { 32 } else { 28 };
+    }
+    fn encode(&self) -> [u8; MAX_RECORD_LENGTH] {
+        let mut buffer = [0; MAX_RECORD_LENGTH];
+        buffer[0..5].clone_from_slice(&self.mode);
+        LittleEndian::write_i32(&mut buffer[5..9], self.total_particles);
+        LittleEndian::write_i32(&mut buffer[9..13], self.total_photons);
+        LittleEndian::write_f32(&mut buffer[13..17], self.max_energy);
+        LittleEndian::write_f32(&mut buffer[17..21], self.min_energy);
+        LittleEndian::write_f32(&mut buffer[21..25], self.total_particles_in_source);
+        buffer
+    }
     fn expected_size(&self) -> usize {
         (self.total_particles as usize + 1) * self.record_size as usize
     }
@@ -224,16 +508,23 @@ impl Header {
                 .total_particles_in_source
                 .approx_eq_ulps(&other.total_particles_in_source, 2)
     }
-    fn merge(&mut self, other: &Header) {
-        assert!(self.mode == other.mode, "Merge mode mismatch");
+    fn merge(&mut self, other: &Header) -> EGSResult<()> {
+        if self.mode != other.mode {
+            return Err(EGSError::ModeMismatch);
+        }
         self.total_particles = self
             .total_particles
             .checked_add(other.total_particles)
-            .expect("Too many particles, i32 overflow");
+            .ok_or_else(|| {
+                EGSError::TooManyParticles(
+                    self.total_particles as u64 + other.total_particles as u64,
+                )
+            })?;
         self.total_photons += other.total_photons;
         self.min_energy = self.min_energy.min(other.min_energy);
         self.max_energy = self.max_energy.max(other.max_energy);
         self.total_particles_in_source += other.total_particles_in_source;
+        Ok(())
     }
 }
 
@@ -267,6 +558,15 @@ impl Record {
         // sets bit 29 alone (phsp_macros.mortran:$GET_E_NPASS_IQ).
         (self.latch >> 29) & 0b11 != 0
     }
+    pub fn particle(&self) -> Particle {
+        if self.latch & (1 << 30) != 0 {
+            Particle::Electron
+        } else if self.latch & (1 << 29) != 0 {
+            Particle::Positron
+        } else {
+            Particle::Photon
+        }
+    }
     pub fn crossed_multiple(&self) -> bool {
         self.latch & (1 << 31) != 0
     }
@@ -279,6 +579,13 @@ impl Record {
     pub fn total_energy(&self) -> f32 {
         self.total_energy.abs()
     }
+    pub fn kinetic_energy(&self) -> f32 {
+        if self.charged() {
+            self.total_energy() - ELECTRON_REST_MASS
+        } else {
+            self.total_energy()
+        }
+    }
     pub fn z_positive(&self) -> bool {
         self.weight.is_sign_positive()
     }
@@ -348,21 +655,11 @@ pub fn randomize(path: &Path, seed: u64) -> EGSResult<()> {
 
         records.shuffle(&mut rng);
 
-        let header = Header {
-            mode: reader.header.mode,
-            total_particles: records.len() as i32,
-            total_photons: 0,
-            max_energy: 0.0,
-            min_energy: 0.0,
-            total_particles_in_source: 0.0,
-            using_zlast: &reader.header.mode == b"MODE2",
-            record_size: reader.header.record_size,
-        };
-        let ofile = File::create(path)?;
-        let mut writer = PHSPWriter::from(ofile, &header)?;
+        let mut writer = PHSPTrackingWriter::create(path, header.using_zlast, 0.0)?;
         for record in records.iter() {
             writer.write(record)?;
         }
+        writer.finish()?;
         records.clear();
     }
     drop(records);
@@ -393,7 +690,7 @@ pub fn combine(input_paths: &[&Path], output_path: &Path, delete: bool) -> EGSRe
     let mut final_header = reader.header;
     for path in input_paths[1..].iter() {
         let reader = PHSPReader::from(File::open(path)?)?;
-        final_header.merge(&reader.header);
+        final_header.merge(&reader.header)?;
     }
     println!("Final header: {:?}", final_header);
     let ofile = File::create(output_path)?;
@@ -455,52 +752,181 @@ pub fn compare(path1: &Path, path2: &Path) -> EGSResult<()> {
 pub fn sample_combine(ipaths: &[&Path], opath: &Path, rate: f64, seed: u64) -> EGSResult<()> {
     assert!(!ipaths.is_empty(), "Cannot combine zero files");
     let mut rng = StdRng::seed_from_u64(seed);
-    let mut header = Header {
-        mode: *b"MODE0",
-        record_size: 28,
-        using_zlast: false,
-        total_particles: 0,
-        total_photons: 0,
-        min_energy: 1000.0,
-        max_energy: 0.0,
-        total_particles_in_source: 0.0,
-    };
-    let mut writer = PHSPWriter::from(File::create(opath)?, &header)?;
+    let mut writer = PHSPTrackingWriter::create(opath, false, 0.0)?;
+    let mut total_particles_in_source = 0.0;
     for path in ipaths.iter() {
-        let reader = PHSPReader::from(File::open(path)?)?;
+        let reader = PHSPReader::open(path)?;
         if reader.header.using_zlast {
             return Err(EGSError::ModeMismatch);
         }
         println!("Found {} particles", reader.header.total_particles);
-        header.total_particles_in_source += reader.header.total_particles_in_source;
+        total_particles_in_source += reader.header.total_particles_in_source;
         let records = reader.filter(|_| rng.random_bool(rate));
         for record in records.map(|r| r.unwrap()) {
-            header.total_particles = header
-                .total_particles
-                .checked_add(1)
-                .expect("Total particles overflow");
-            if !record.charged() {
-                header.total_photons += 1;
-            }
-            let energy = record.total_energy();
-            header.min_energy = header.min_energy.min(energy);
-            header.max_energy = header.max_energy.max(energy);
             writer.write(&record)?;
         }
-        println!("Now have {} particles", header.total_particles);
-    }
-    header.total_particles_in_source *= rate as f32;
-    drop(writer);
-    // write out the header
-    let ofile = OpenOptions::new()
-        .write(true)
-        .create(true)
-        .truncate(false)
-        .open(opath)?;
-    PHSPWriter::from(ofile, &header)?;
+        println!("Now have {} particles", writer.stats.total_particles);
+    }
+    writer.set_total_particles_in_source(total_particles_in_source * rate as f32);
+    writer.finish()?;
+    Ok(())
+}
+
+pub fn slice(input_path: &Path, output_path: &Path, start: u64, count: u64) -> EGSResult<()> {
+    let mut reader = PHSPReader::open(input_path)?;
+    let total = reader.len();
+    let header = reader.header;
+    let mut writer = PHSPTrackingWriter::create(output_path, header.using_zlast, 0.0)?;
+    for record in reader.range(start..start.saturating_add(count))? {
+        writer.write(&record?)?;
+    }
+    // the slice stands in for its share of the source histories
+    let kept = writer.stats.total_particles;
+    if total > 0 {
+        writer.set_total_particles_in_source(
+            header.total_particles_in_source * kept as f32 / total as f32,
+        );
+    }
+    writer.finish()?;
+    println!(
+        "Extracted {} of {} records starting at {}",
+        kept, total, start
+    );
+    Ok(())
+}
+
+/// Recomputes the header of a phase space from its records. The number of records is
+/// taken from the file size, so a trailing partial record (from a killed job) is dropped.
+/// `total_particles_in_source` is kept unless a new value is given.
+pub fn repair(
+    input_path: &Path,
+    output_path: &Path,
+    total_particles_in_source: Option<f32>,
+) -> EGSResult<Header> {
+    let file = File::open(input_path)?;
+    let actual_size = (file.metadata()?).len();
+    let mut reader = PHSPReader::new(file, None)?;
+    let old_header = reader.header;
+    let record_size = old_header.record_size;
+    let complete_records = actual_size.saturating_sub(record_size) / record_size;
+    reader.header.total_particles = i32::try_from(complete_records)
+        .map_err(|_| EGSError::TooManyParticles(complete_records))?;
+    let mut stats = HeaderStats::default();
+    for record in reader {
+        stats.add(&record?)?;
+    }
+    let mut header = old_header;
+    stats.apply(&mut header);
+    if let Some(total_particles_in_source) = total_particles_in_source {
+        header.total_particles_in_source = total_particles_in_source;
+    }
+    let trailing = actual_size.saturating_sub(record_size) % record_size;
+    if trailing > 0 {
+        println!("Dropping {} bytes of a trailing partial record", trailing);
+    }
+    if input_path == output_path {
+        println!("Repairing {} in place", input_path.display());
+        let mut ofile = OpenOptions::new().write(true).open(output_path)?;
+        ofile.set_len(header.expected_size() as u64)?;
+        ofile.write_all(&header.encode()[..HEADER_LENGTH])?;
+    } else {
+        println!(
+            "Repairing {} and saving to {}",
+            input_path.display(),
+            output_path.display()
+        );
+        let mut reader = PHSPReader::new(File::open(input_path)?, None)?;
+        reader.header.total_particles = header.total_particles;
+        let mut writer = PHSPWriter::create(output_path, &header)?;
+        for record in reader {
+            writer.write(&record?)?;
+        }
+    }
+    println!("                   Before\t\tAfter");
+    println!(
+        "Total particles:   {0: <10}\t\t{1}",
+        old_header.total_particles, header.total_particles
+    );
+    println!(
+        "Total photons:     {0: <10}\t\t{1}",
+        old_header.total_photons, header.total_photons
+    );
+    println!(
+        "Minimum energy:    {0: <10}\t\t{1}",
+        old_header.min_energy, header.min_energy
+    );
+    println!(
+        "Maximum energy:    {0: <10}\t\t{1}",
+        old_header.max_energy, header.max_energy
+    );
+    println!(
+        "Source particles:  {0: <10}\t\t{1}",
+        old_header.total_particles_in_source, header.total_particles_in_source
+    );
+    Ok(header)
+}
+
+fn write_converted<W: Write>(
+    reader: PHSPReader,
+    output: W,
+    header: &Header,
+    zlast: f32,
+) -> EGSResult<()> {
+    let mut writer = PHSPWriter::new(output, header)?;
+    for record in reader {
+        let mut record = record?;
+        record.zlast = if header.using_zlast {
+            Some(record.zlast.unwrap_or(zlast))
+        } else {
+            None
+        };
+        writer.write(&record)?;
+    }
+    writer.into_inner()?;
     Ok(())
 }
 
+/// Rewrites a phase space as MODE2 (`using_zlast`) or MODE0. Going from MODE0 to MODE2
+/// every record gets `zlast`, or `ZLAST_UNKNOWN` if it isn't given; going to MODE0 the
+/// ZLAST column is dropped. `zlast` is an error for any other conversion, since it would
+/// be ignored.
+pub fn convert_mode(
+    input_path: &Path,
+    output_path: &Path,
+    using_zlast: bool,
+    zlast: Option<f32>,
+) -> EGSResult<()> {
+    let reader = PHSPReader::open(input_path)?;
+    let adding_zlast = using_zlast && !reader.header.using_zlast;
+    if zlast.is_some() && !adding_zlast {
+        return Err(EGSError::UnusedZlast);
+    }
+    let zlast = zlast.unwrap_or(ZLAST_UNKNOWN);
+    let mut header = reader.header;
+    header.set_using_zlast(using_zlast);
+    println!(
+        "Converting {} from {} to {}",
+        input_path.display(),
+        String::from_utf8_lossy(&reader.header.mode),
+        String::from_utf8_lossy(&header.mode)
+    );
+    if input_path == output_path {
+        // the record size changes, so in place conversions go through a temporary file,
+        // which is removed if anything goes wrong
+        let (temporary_path, file) = filter::create_temporary(output_path, "convert-mode")?;
+        let result = write_converted(reader, file, &header, zlast).and_then(|_| {
+            rename(&temporary_path, output_path)?;
+            Ok(())
+        });
+        if result.is_err() {
+            let _ = remove_file(&temporary_path);
+        }
+        result
+    } else {
+        write_converted(reader, File::create(output_path)?, &header, zlast)
+    }
+}
+
 pub fn translate(input_path: &Path, output_path: &Path, x: f32, y: f32) -> EGSResult<()> {
     let ifile = File::open(input_path)?;
     let reader = PHSPReader::from(ifile)?;
@@ -574,12 +1000,93 @@ pub fn transform(input_path: &Path, output_path: &Path, matrix: &[[f32; 3]; 3])
     Ok(())
 }
 
+/// Reshapes the radial fluence out to `max_radius` to follow `target`, a function of the
+/// radius in cm. The current fluence is binned in `number_bins` rings and every particle's
+/// weight is scaled by the ratio of the target to the current fluence at the center of
+/// its ring. Only the shape of the target matters: the total weight inside `max_radius`
+/// is kept, and particles outside it are unchanged.
 pub fn reweight(
     input_path: &Path,
     output_path: &Path,
-    f: &dyn Fn(f32) -> f32,
-    _number_bins: usize,
-    _max_radius: f32,
+    target: &dyn Fn(f32) -> f32,
+    number_bins: usize,
+    max_radius: f32,
+) -> EGSResult<()> {
+    let zones = Zones::new(ZoneShape::Annular, number_bins, max_radius as f64)?;
+    let mut current = vec![0.0_f64; number_bins];
+    for record in PHSPReader::open(input_path)? {
+        let record = record?;
+        if let Some(zone) = zones.zone(record.x_cm, record.y_cm) {
+            current[zone] += record.get_weight() as f64;
+        }
+    }
+    let edges = zones.edges();
+    let mut factors = vec![0.0_f64; number_bins];
+    // weight the target gives each ring, for the rings holding particles
+    let mut total_target = 0.0;
+    for zone in 0..number_bins {
+        let center = (edges[zone] + edges[zone + 1]) / 2.0;
+        let value = target(center as f32) as f64;
+        if !value.is_finite() || value < 0.0 {
+            return Err(EGSError::BadTarget(format!(
+                "{} at radius {} is negative or not finite",
+                value, center
+            )));
+        }
+        if current[zone] > 0.0 {
+            // target / (current / area), up to the normalization below
+            factors[zone] = value * zones.area(zone) / current[zone];
+            total_target += value * zones.area(zone);
+        }
+    }
+    if total_target == 0.0 {
+        return Err(EGSError::BadTarget(
+            "zero everywhere there are particles".to_string(),
+        ));
+    }
+    let normalization = current.iter().sum::<f64>() / total_target;
+    for factor in factors.iter_mut() {
+        *factor *= normalization;
+    }
+    reweight_records(input_path, output_path, &|record: &Record| {
+        match zones.zone(record.x_cm, record.y_cm) {
+            Some(zone) => factors[zone] as f32,
+            None => 1.0,
+        }
+    })
+}
+
+/// Multiplies the weight of every particle by `f` of the particle, then rescales all
+/// weights so their total is unchanged. Factors must be finite and not negative.
+pub fn reweight_records(
+    input_path: &Path,
+    output_path: &Path,
+    f: &dyn Fn(&Record) -> f32,
+) -> EGSResult<()> {
+    let reader = PHSPReader::from(File::open(input_path)?)?;
+    let mut sum_old_weight = 0.0_f64;
+    let mut sum_new_weight = 0.0_f64;
+    for (index, record) in reader.enumerate() {
+        let record = record?;
+        let factor = f(&record);
+        if !factor.is_finite() || factor < 0.0 {
+            return Err(EGSError::BadWeightFactor(index, factor));
+        }
+        let w = record.get_weight() as f64;
+        sum_old_weight += w;
+        sum_new_weight += w * factor as f64;
+    }
+    let factor = (sum_old_weight / sum_new_weight) as f32;
+    scale_weights(input_path, output_path, &|record: &Record| f(record) * factor)
+}
+
+/// Multiplies the weight of every particle by `f` of the particle, keeping its sign. Unlike
+/// `reweight_records` the total weight changes, as it should for an attenuator. Factors
+/// must be finite and not negative.
+pub fn scale_weights(
+    input_path: &Path,
+    output_path: &Path,
+    f: &dyn Fn(&Record) -> f32,
 ) -> EGSResult<()> {
     if input_path == output_path {
         println!("Reweighting in-place");
@@ -587,17 +1094,7 @@ pub fn reweight(
         println!("Reweighting and saving to {}", output_path.display());
     }
 
-    let reader1 = PHSPReader::from(File::open(input_path)?)?;
-    let mut sum_old_weight = 0.0_f32;
-    let mut sum_new_weight = 0.0_f32;
-    for record in reader1.map(|r| r.unwrap()) {
-        let w = record.get_weight();
-        sum_old_weight += w;
-        let r = (record.x_cm * record.x_cm + record.y_cm * record.y_cm).sqrt();
-        sum_new_weight += w * f(r);
-    }
-
-    let reader2 = PHSPReader::from(File::open(input_path)?)?;
+    let reader = PHSPReader::from(File::open(input_path)?)?;
     let output_file = if input_path == output_path {
         OpenOptions::new()
             .write(true)
@@ -607,29 +1104,143 @@ pub fn reweight(
     } else {
         File::create(output_path)?
     };
-    let mut writer = PHSPWriter::from(output_file, &reader2.header)?;
-    let factor = sum_old_weight / sum_new_weight;
-    for mut record in reader2.map(|r| r.unwrap()) {
-        let r = (record.x_cm * record.x_cm + record.y_cm * record.y_cm).sqrt();
-        record.weight *= f(r) * factor;
+    let mut writer = PHSPWriter::from(output_file, &reader.header)?;
+    for (index, record) in reader.enumerate() {
+        let mut record = record?;
+        let factor = f(&record);
+        if !factor.is_finite() || factor < 0.0 {
+            return Err(EGSError::BadWeightFactor(index, factor));
+        }
+        let new_weight = record.get_weight() * factor;
+        record.set_weight(new_weight);
         writer.write(&record)?;
     }
     Ok(())
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use std::sync::atomic::{AtomicU64, Ordering};
+/// The Kish effective number of particles, (Σw)² / Σw², of one particle selection before
+/// and after reweighting, with the weight of the selection outside the binning, which was
+/// left unchanged.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct EffectiveChange {
+    pub selection: Selection,
+    pub before: f64,
+    pub after: f64,
+    pub outside_weight: f64,
+}
 
-    static COUNTER: AtomicU64 = AtomicU64::new(0);
+// per bin weight sums of one selection, with the particles outside the binning
+#[derive(Debug, Clone)]
+struct SpectrumSums {
+    weight: Vec<f64>,
+    weight_squared: Vec<f64>,
+    outside_weight: f64,
+    outside_weight_squared: f64,
+}
+
+/// Reshapes the kinetic energy spectrum of each selected particle type to follow `target`,
+/// a spectrum per MeV. Each type is binned separately and every particle's weight is
+/// scaled by the ratio of the target to the current spectrum at the center of its bin,
+/// keeping the total weight of the type within the binning. A particle belongs to the
+/// first selection it matches; particles outside the binning or not selected are unchanged.
+pub fn reweight_spectrum(
+    input_path: &Path,
+    output_path: &Path,
+    target: &dyn Fn(f64) -> f64,
+    binning: &Binning,
+    selections: &[Selection],
+) -> EGSResult<Vec<EffectiveChange>> {
+    let empty = SpectrumSums {
+        weight: vec![0.0; binning.bins],
+        weight_squared: vec![0.0; binning.bins],
+        outside_weight: 0.0,
+        outside_weight_squared: 0.0,
+    };
+    let mut sums = vec![empty; selections.len()];
+    for record in PHSPReader::open(input_path)? {
+        let record = record?;
+        let i = match selections.iter().position(|s| s.matches(&record)) {
+            Some(i) => i,
+            None => continue,
+        };
+        let weight = record.get_weight() as f64;
+        match binning.bin(record.kinetic_energy() as f64) {
+            Some(bin) => {
+                sums[i].weight[bin] += weight;
+                sums[i].weight_squared[bin] += weight * weight;
+            }
+            None => {
+                sums[i].outside_weight += weight;
+                sums[i].outside_weight_squared += weight * weight;
+            }
+        }
+    }
 
-    fn tmp_path(label: &str) -> std::path::PathBuf {
-        let n = COUNTER.fetch_add(1, Ordering::SeqCst);
-        let pid = std::process::id();
-        std::env::temp_dir().join(format!("beamdpr_test_{}_{}_{}.egsphsp1", label, pid, n))
+    let edges = binning.edges();
+    let mut factors = vec![vec![0.0_f64; binning.bins]; selections.len()];
+    let mut changes = Vec::new();
+    for (i, selection) in selections.iter().enumerate() {
+        let sums = &sums[i];
+        // weight the target gives each bin, for the bins holding particles
+        let mut total_target = 0.0;
+        for bin in 0..binning.bins {
+            let center = (edges[bin] + edges[bin + 1]) / 2.0;
+            let value = target(center);
+            if !value.is_finite() || value < 0.0 {
+                return Err(EGSError::BadTarget(format!(
+                    "{} at {} MeV is negative or not finite",
+                    value, center
+                )));
+            }
+            if sums.weight[bin] > 0.0 {
+                let width = edges[bin + 1] - edges[bin];
+                factors[i][bin] = value * width / sums.weight[bin];
+                total_target += value * width;
+            }
+        }
+        let total: f64 = sums.weight.iter().sum();
+        if total > 0.0 && total_target == 0.0 {
+            return Err(EGSError::BadTarget(format!(
+                "zero everywhere there are {}",
+                selection
+            )));
+        }
+        // no particles of this type to reshape leaves the factors at zero, unused
+        let normalization = if total > 0.0 { total / total_target } else { 0.0 };
+        let mut weight_after = sums.outside_weight;
+        let mut weight_squared_after = sums.outside_weight_squared;
+        let bins = sums.weight.iter().zip(sums.weight_squared.iter());
+        for (factor, (weight, weight_squared)) in factors[i].iter_mut().zip(bins) {
+            *factor *= normalization;
+            weight_after += *factor * weight;
+            weight_squared_after += *factor * *factor * weight_squared;
+        }
+        let weight_before = total + sums.outside_weight;
+        let weight_squared_before =
+            sums.weight_squared.iter().sum::<f64>() + sums.outside_weight_squared;
+        changes.push(EffectiveChange {
+            selection: *selection,
+            before: weight_before * weight_before / weight_squared_before,
+            after: weight_after * weight_after / weight_squared_after,
+            outside_weight: sums.outside_weight,
+        });
     }
 
+    reweight_records(input_path, output_path, &|record: &Record| {
+        let i = selections.iter().position(|s| s.matches(record));
+        match (i, binning.bin(record.kinetic_energy() as f64)) {
+            (Some(i), Some(bin)) => factors[i][bin] as f32,
+            _ => 1.0,
+        }
+    })?;
+    Ok(changes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::tmp_path;
+
     fn make_record(latch: u32, energy: f32, x: f32, y: f32, zlast: Option<f32>) -> Record {
         Record {
             latch,
@@ -678,12 +1289,31 @@ mod tests {
         write_phsp(&input, &header, &records);
 
         reweight(&input, &output, &|r| r + 1.0, 10, 5.0).unwrap();
+        let reader = PHSPReader::from(File::open(&output).unwrap()).unwrap();
+        let binned: Vec<Record> = reader.map(|r| r.unwrap()).collect();
 
+        reweight_records(&input, &output, &|record| record.x_cm + 1.0).unwrap();
         let reader = PHSPReader::from(File::open(&output).unwrap()).unwrap();
         let out: Vec<Record> = reader.map(|r| r.unwrap()).collect();
         let _ = remove_file(&input);
         let _ = remove_file(&output);
 
+        // the particles fall in rings of width 0.5 centered on 0.25, 1.25 and 2.25 with
+        // areas 0.25 pi, 1.25 pi and 2.25 pi. Each holds a weight of 2, so the new weights
+        // go as target * area: 1.25 * 0.25, 2.25 * 1.25 and 3.25 * 2.25, scaled to total 6.
+        let shares = [0.3125_f32, 2.8125, 7.3125];
+        let total: f32 = shares.iter().sum();
+        for (i, r) in binned.iter().enumerate() {
+            let expected = 6.0 * shares[i] / total;
+            assert!(
+                (r.weight - expected).abs() < 1e-4,
+                "record {}: expected binned weight {}, got {}",
+                i,
+                expected,
+                r.weight
+            );
+        }
+
         // sum_old = 6, sum_new = 2*1 + 2*2 + 2*3 = 12, factor = 0.5
         // expected = original_weight * f(r) * factor = 2 * (r+1) * 0.5 = r + 1
         let expected = [1.0_f32, 2.0, 3.0];
@@ -698,6 +1328,48 @@ mod tests {
         }
     }
 
+    #[test]
+    fn reweight_spectrum_reshapes_each_selection() {
+        let input = tmp_path("reweight_spectrum_in");
+        let output = tmp_path("reweight_spectrum_out");
+        let header = Header {
+            mode: *b"MODE0",
+            total_particles: 3,
+            total_photons: 2,
+            min_energy: 1.0,
+            max_energy: 3.0,
+            total_particles_in_source: 10.0,
+            record_size: 28,
+            using_zlast: false,
+        };
+        let mut records = vec![
+            make_record(0, 1.0, 0.0, 0.0, None),
+            make_record(0, 3.0, 0.0, 0.0, None),
+            make_record(1 << 30, 2.0, 0.0, 0.0, None),
+        ];
+        records[0].weight = -1.0;
+        write_phsp(&input, &header, &records);
+
+        // three times as many photons per MeV below 2 MeV as above
+        let target = |e: f64| if e < 2.0 { 3.0 } else { 1.0 };
+        let binning = Binning::new(0.0, 4.0, 2, analysis::Scale::Linear).unwrap();
+        let changes =
+            reweight_spectrum(&input, &output, &target, &binning, &[Selection::Photons]).unwrap();
+        let reader = PHSPReader::from(File::open(&output).unwrap()).unwrap();
+        let out: Vec<Record> = reader.map(|r| r.unwrap()).collect();
+        let _ = remove_file(&input);
+        let _ = remove_file(&output);
+
+        // the photon weight of 2 is split 3:1, keeping the z direction
+        assert!((out[0].weight + 1.5).abs() < 1e-6);
+        assert!((out[1].weight - 0.5).abs() < 1e-6);
+        assert_eq!(out[2].weight, 1.0);
+        assert_eq!(changes.len(), 1);
+        assert!((changes[0].before - 2.0).abs() < 1e-9);
+        assert!((changes[0].after - 1.6).abs() < 1e-9);
+        assert_eq!(changes[0].outside_weight, 0.0);
+    }
+
     #[test]
     fn reweight_uses_abs_weight_for_normalization() {
         // WT sign carries the Z direction (per EGSnrc / lib.rs:38).
@@ -934,9 +1606,16 @@ mod tests {
         );
     }
 
+    fn write_phsp_to_vec(header: &Header, records: &[Record]) -> Vec<u8> {
+        let mut writer = PHSPWriter::new(Vec::new(), header).unwrap();
+        for r in records {
+            writer.write(r).unwrap();
+        }
+        writer.into_inner().unwrap()
+    }
+
     #[test]
     fn mode2_writer_preserves_zlast() {
-        let path = tmp_path("mode2_zlast");
         let header = Header {
             mode: *b"MODE2",
             total_particles: 2,
@@ -949,14 +1628,220 @@ mod tests {
         };
         let r1 = make_record(0, 1.0, 0.0, 0.0, Some(7.25));
         let r2 = make_record(1 << 30, 2.0, 1.0, 1.0, Some(-3.5));
-        write_phsp(&path, &header, &[r1, r2]);
+        let bytes = write_phsp_to_vec(&header, &[r1, r2]);
+        assert_eq!(bytes.len(), 3 * 32);
 
-        let reader = PHSPReader::from(File::open(&path).unwrap()).unwrap();
+        let reader = PHSPReader::new(bytes.as_slice(), Some(bytes.len() as u64)).unwrap();
         let records: Vec<Record> = reader.map(|r| r.unwrap()).collect();
-        let _ = remove_file(&path);
 
         assert_eq!(records.len(), 2);
         assert_eq!(records[0].zlast, Some(7.25), "first zlast not preserved");
         assert_eq!(records[1].zlast, Some(-3.5), "second zlast not preserved");
     }
+
+    #[test]
+    fn reader_skips_header_padding_on_unbuffered_source() {
+        // a source that hands out one byte per read, like a pipe under load
+        struct Trickle<'a>(&'a [u8]);
+        impl Read for Trickle<'_> {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                if self.0.is_empty() || buf.is_empty() {
+                    return Ok(0);
+                }
+                buf[0] = self.0[0];
+                self.0 = &self.0[1..];
+                Ok(1)
+            }
+        }
+        let header = Header {
+            mode: *b"MODE0",
+            total_particles: 1,
+            total_photons: 1,
+            min_energy: 1.0,
+            max_energy: 1.0,
+            total_particles_in_source: 1.0,
+            record_size: 28,
+            using_zlast: false,
+        };
+        let bytes = write_phsp_to_vec(&header, &[make_record(0, 1.0, 3.0, 4.0, None)]);
+        let reader = PHSPReader::new(Trickle(&bytes), None).unwrap();
+        let records: Vec<Record> = reader.map(|r| r.unwrap()).collect();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].x_cm, 3.0);
+        assert_eq!(records[0].y_cm, 4.0);
+    }
+
+    #[test]
+    fn random_access_by_record_index() {
+        let header = Header {
+            mode: *b"MODE2",
+            total_particles: 5,
+            total_photons: 5,
+            min_energy: 0.0,
+            max_energy: 4.0,
+            total_particles_in_source: 5.0,
+            record_size: 32,
+            using_zlast: true,
+        };
+        let records: Vec<Record> = (0..5)
+            .map(|i| make_record(0, i as f32, i as f32, 0.0, Some(i as f32)))
+            .collect();
+        let bytes = write_phsp_to_vec(&header, &records);
+        let mut reader = PHSPReader::new(io::Cursor::new(bytes), None).unwrap();
+
+        assert_eq!(reader.len(), 5);
+        assert_eq!(reader.get(3).unwrap().x_cm, 3.0);
+        assert_eq!(reader.get(0).unwrap().x_cm, 0.0);
+        assert!(reader.get(5).is_err());
+
+        let xs: Vec<f32> = reader.range(1..3).unwrap().map(|r| r.unwrap().x_cm).collect();
+        assert_eq!(xs, vec![1.0, 2.0]);
+        let xs: Vec<f32> = reader.range(3..100).unwrap().map(|r| r.unwrap().x_cm).collect();
+        assert_eq!(xs, vec![3.0, 4.0]);
+
+        reader.seek_record(4).unwrap();
+        assert_eq!(reader.next().unwrap().unwrap().zlast, Some(4.0));
+        assert!(reader.next().is_none());
+        assert!(reader.seek_record(6).is_err());
+    }
+
+    #[test]
+    fn tracking_writer_computes_header_on_finish() {
+        let mut buffer = io::Cursor::new(Vec::new());
+        let mut writer = PHSPTrackingWriter::new(&mut buffer, false, 50.0).unwrap();
+        writer.write(&make_record(0, 0.1, 0.0, 0.0, None)).unwrap();
+        writer.write(&make_record(0, -0.3, 0.0, 0.0, None)).unwrap();
+        writer.write(&make_record(1 << 30, 0.6, 0.0, 0.0, None)).unwrap();
+        writer.write(&make_record(1 << 29, 0.9, 0.0, 0.0, None)).unwrap();
+        let header = writer.finish().unwrap();
+
+        let bytes = buffer.into_inner();
+        let reader = PHSPReader::new(bytes.as_slice(), Some(bytes.len() as u64)).unwrap();
+        for h in [header, reader.header] {
+            assert_eq!(h.total_particles, 4);
+            assert_eq!(h.total_photons, 2);
+            assert_eq!(h.total_particles_in_source, 50.0);
+            // kinetic energies, the minimum taken over charged particles only
+            assert!((h.max_energy - 0.389).abs() < 1e-6, "max {}", h.max_energy);
+            assert!((h.min_energy - 0.089).abs() < 1e-6, "min {}", h.min_energy);
+        }
+    }
+
+    #[test]
+    fn tracking_writer_rewrites_header_when_dropped() {
+        let mut buffer = io::Cursor::new(Vec::new());
+        {
+            let mut writer = PHSPTrackingWriter::new(&mut buffer, true, 1.0).unwrap();
+            writer.write(&make_record(0, 0.5, 0.0, 0.0, Some(1.0))).unwrap();
+            writer.write(&make_record(0, 1.5, 0.0, 0.0, Some(2.0))).unwrap();
+        }
+        let bytes = buffer.into_inner();
+        let reader = PHSPReader::new(bytes.as_slice(), Some(bytes.len() as u64)).unwrap();
+        assert_eq!(reader.header.total_particles, 2);
+        assert_eq!(reader.header.total_photons, 2);
+        assert_eq!(reader.header.min_energy, 0.5);
+        assert_eq!(reader.header.max_energy, 1.5);
+        assert_eq!(reader.count(), 2);
+    }
+
+    #[test]
+    fn convert_mode_round_trip() {
+        let input = tmp_path("mode0_in");
+        let mode2 = tmp_path("mode2_out");
+        let header = Header {
+            mode: *b"MODE0",
+            total_particles: 2,
+            total_photons: 1,
+            min_energy: 0.5,
+            max_energy: 1.0,
+            total_particles_in_source: 10.0,
+            record_size: 28,
+            using_zlast: false,
+        };
+        let records = [
+            make_record(0, 1.0, 1.0, 2.0, None),
+            make_record(1 << 30, 1.011, 3.0, 4.0, None),
+        ];
+        write_phsp(&input, &header, &records);
+
+        convert_mode(&input, &mode2, true, None).unwrap();
+        let reader = PHSPReader::open(&mode2).unwrap();
+        assert!(reader.map(|r| r.unwrap()).all(|r| r.zlast.unwrap().is_nan()));
+        convert_mode(&input, &mode2, true, Some(12.5)).unwrap();
+        let reader = PHSPReader::open(&mode2).unwrap();
+        assert_eq!(&reader.header.mode, b"MODE2");
+        assert_eq!(reader.header.record_size, 32);
+        assert!(reader.header.using_zlast);
+        assert_eq!(reader.header.total_particles_in_source, 10.0);
+        let out: Vec<Record> = reader.map(|r| r.unwrap()).collect();
+        assert!(out.iter().all(|r| r.zlast == Some(12.5)));
+
+        // zlast would be ignored, so it is rejected
+        for using_zlast in [true, false] {
+            match convert_mode(&mode2, &mode2, using_zlast, Some(1.0)) {
+                Err(EGSError::UnusedZlast) => (),
+                other => panic!("expected UnusedZlast, got {:?}", other),
+            }
+        }
+
+        // and back again, in place
+        convert_mode(&mode2, &mode2, false, None).unwrap();
+        let bytes = std::fs::read(&mode2).unwrap();
+        let _ = remove_file(&input);
+        let _ = remove_file(&mode2);
+        assert_eq!(bytes.len(), 3 * 28);
+        let reader = PHSPReader::new(bytes.as_slice(), Some(bytes.len() as u64)).unwrap();
+        assert_eq!(&reader.header.mode, b"MODE0");
+        for (original, converted) in records.iter().zip(reader.map(|r| r.unwrap())) {
+            assert!(original.similar_to(&converted));
+        }
+    }
+
+    #[test]
+    fn write_header_rewrites_in_place() {
+        let mut header = Header {
+            mode: *b"MODE0",
+            total_particles: 0,
+            total_photons: 0,
+            min_energy: 0.0,
+            max_energy: 0.0,
+            total_particles_in_source: 0.0,
+            record_size: 28,
+            using_zlast: false,
+        };
+        let mut writer = PHSPWriter::new(io::Cursor::new(Vec::new()), &header).unwrap();
+        writer.write(&make_record(0, 2.0, 0.0, 0.0, None)).unwrap();
+        header.total_particles = 1;
+        header.total_photons = 1;
+        header.max_energy = 2.0;
+        writer.write_header(&header).unwrap();
+        writer.write(&make_record(0, 2.0, 0.0, 0.0, None)).unwrap();
+        let bytes = writer.into_inner().unwrap().into_inner();
+        assert_eq!(bytes.len(), 3 * 28);
+
+        let reader = PHSPReader::new(bytes.as_slice(), None).unwrap();
+        assert_eq!(reader.header.total_particles, 1);
+        assert_eq!(reader.header.max_energy, 2.0);
+
+        let mut writer = PHSPWriter::new(io::Cursor::new(Vec::new()), &header).unwrap();
+        header.set_using_zlast(true);
+        assert!(matches!(writer.write_header(&header), Err(EGSError::ModeMismatch)));
+    }
+
+    #[test]
+    fn header_counts_report_overflow() {
+        let mut stats = HeaderStats {
+            total_particles: i32::MAX,
+            ..HeaderStats::default()
+        };
+        let result = stats.add(&make_record(0, 2.0, 0.0, 0.0, None));
+        assert!(matches!(result, Err(EGSError::TooManyParticles(2_147_483_648))));
+
+        let mut header = Header::empty(false);
+        header.total_particles = i32::MAX;
+        let mut other = Header::empty(false);
+        other.total_particles = 2;
+        assert!(matches!(header.merge(&other), Err(EGSError::TooManyParticles(2_147_483_649))));
+        assert!(matches!(header.merge(&Header::empty(true)), Err(EGSError::ModeMismatch)));
+    }
 }
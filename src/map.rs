@@ -0,0 +1,615 @@
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::path::Path;
+
+use crate::analysis::{histories, Quantity, Selection, Tally};
+use crate::{scale_weights, EGSError, EGSResult, PHSPReader, Particle, Record};
+
+/// A window of the scoring plane divided into `columns` by `rows` square-ish pixels.
+/// Row 0 is the top of the window (largest y), as in an image.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Grid {
+    pub x_min: f32,
+    pub x_max: f32,
+    pub y_min: f32,
+    pub y_max: f32,
+    pub columns: usize,
+    pub rows: usize,
+}
+
+/// The most pixels a grid may have (4096 x 4096), so a mistyped resolution is an error
+/// rather than an attempt to allocate all of memory.
+pub const MAX_PIXELS: usize = 1 << 24;
+
+fn check_window(x_min: f32, x_max: f32, y_min: f32, y_max: f32) -> EGSResult<()> {
+    // NaN edges would pass the comparisons below, and infinite ones give no pixels
+    let finite = x_min.is_finite() && x_max.is_finite() && y_min.is_finite() && y_max.is_finite();
+    if !finite || x_min >= x_max || y_min >= y_max {
+        return Err(EGSError::BadBinning(format!(
+            "empty window x {} to {}, y {} to {}",
+            x_min, x_max, y_min, y_max
+        )));
+    }
+    Ok(())
+}
+
+impl Grid {
+    /// Pixels of (at most) `resolution` cm covering the window.
+    pub fn new(x_min: f32, x_max: f32, y_min: f32, y_max: f32, resolution: f32) -> EGSResult<Grid> {
+        check_window(x_min, x_max, y_min, y_max)?;
+        if resolution.is_nan() || resolution <= 0.0 {
+            return Err(EGSError::BadBinning(format!(
+                "resolution {} is not positive",
+                resolution
+            )));
+        }
+        // the float to integer casts saturate, and the pixel count is checked below
+        let columns = ((x_max - x_min) / resolution).ceil() as usize;
+        let rows = ((y_max - y_min) / resolution).ceil() as usize;
+        Grid::sized(x_min, x_max, y_min, y_max, columns, rows)
+    }
+
+    /// `columns` by `rows` pixels covering the window, for an existing image or matrix.
+    pub fn sized(
+        x_min: f32,
+        x_max: f32,
+        y_min: f32,
+        y_max: f32,
+        columns: usize,
+        rows: usize,
+    ) -> EGSResult<Grid> {
+        check_window(x_min, x_max, y_min, y_max)?;
+        let pixels = columns.saturating_mul(rows);
+        if columns == 0 || rows == 0 || pixels > MAX_PIXELS {
+            return Err(EGSError::BadBinning(format!(
+                "{} columns x {} rows, which must be between 1 and {} pixels",
+                columns, rows, MAX_PIXELS
+            )));
+        }
+        Ok(Grid {
+            x_min,
+            x_max,
+            y_min,
+            y_max,
+            columns,
+            rows,
+        })
+    }
+
+    /// The column and row holding `(x, y)`, if any. A NaN position is in no pixel.
+    pub fn pixel(&self, x: f32, y: f32) -> Option<(usize, usize)> {
+        if x.is_nan() || y.is_nan() {
+            return None;
+        }
+        if x < self.x_min || x >= self.x_max || y <= self.y_min || y > self.y_max {
+            return None;
+        }
+        let column = (x - self.x_min) / (self.x_max - self.x_min) * self.columns as f32;
+        let row = (self.y_max - y) / (self.y_max - self.y_min) * self.rows as f32;
+        Some((
+            (column as usize).min(self.columns - 1),
+            (row as usize).min(self.rows - 1),
+        ))
+    }
+
+    /// Area of one pixel in cm².
+    pub fn pixel_area(&self) -> f64 {
+        (self.x_max - self.x_min) as f64 / self.columns as f64 * (self.y_max - self.y_min) as f64
+            / self.rows as f64
+    }
+}
+
+/// A value for every pixel of a grid, row by row from the top.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Map {
+    pub grid: Grid,
+    pub values: Vec<f64>,
+}
+
+impl Map {
+    pub fn get(&self, column: usize, row: usize) -> f64 {
+        self.values[row * self.grid.columns + column]
+    }
+
+    /// The value of the pixel holding (x, y), if it is inside the window.
+    pub fn value_at(&self, x: f32, y: f32) -> Option<f64> {
+        self.grid
+            .pixel(x, y)
+            .map(|(column, row)| self.get(column, row))
+    }
+
+    fn max(&self) -> f64 {
+        self.values
+            .iter()
+            .copied()
+            .filter(|v| v.is_finite())
+            .fold(0.0, f64::max)
+    }
+
+    // each pixel scaled to 0-255 of the largest value
+    fn levels(&self) -> Vec<u8> {
+        let max = self.max();
+        self.values
+            .iter()
+            .map(|v| {
+                if max > 0.0 && v.is_finite() {
+                    (v / max * 255.0).round().clamp(0.0, 255.0) as u8
+                } else {
+                    0
+                }
+            })
+            .collect()
+    }
+}
+
+/// Fluence, energy fluence or mean energy per pixel per incident particle, or the number
+/// of particles in each pixel, for the selected particles.
+pub fn fluence_map<R: Read>(
+    reader: PHSPReader<R>,
+    grid: &Grid,
+    selection: Selection,
+    quantity: Quantity,
+) -> EGSResult<Map> {
+    let histories = histories(reader.header.total_particles_in_source);
+    let mut tallies = vec![Tally::default(); grid.columns * grid.rows];
+    for record in reader {
+        let record = record?;
+        if !selection.matches(&record) {
+            continue;
+        }
+        if let Some((column, row)) = grid.pixel(record.x_cm, record.y_cm) {
+            let value = match quantity {
+                Quantity::Fluence | Quantity::Count => 1.0,
+                _ => record.kinetic_energy() as f64,
+            };
+            tallies[row * grid.columns + column].add(record.get_weight() as f64, value);
+        }
+    }
+    let scale = grid.pixel_area() * histories;
+    Ok(Map {
+        grid: *grid,
+        values: tallies.iter().map(|t| t.result(quantity, scale).0).collect(),
+    })
+}
+
+/// Writes the map as whitespace separated rows of numbers, top row first.
+pub fn write_matrix<W: Write>(map: &Map, mut output: W) -> EGSResult<()> {
+    let grid = &map.grid;
+    writeln!(
+        output,
+        "# {} columns x {} rows, x {} to {} cm, y {} to {} cm, first row at the top",
+        grid.columns, grid.rows, grid.x_min, grid.x_max, grid.y_min, grid.y_max
+    )?;
+    for row in 0..grid.rows {
+        let line: Vec<String> = (0..grid.columns)
+            .map(|column| format!("{:e}", map.get(column, row)))
+            .collect();
+        writeln!(output, "{}", line.join(" "))?;
+    }
+    Ok(())
+}
+
+/// Writes the map as a binary greyscale PGM, black for zero and white for the maximum.
+pub fn write_pgm<W: Write>(map: &Map, mut output: W) -> EGSResult<()> {
+    write!(output, "P5\n{} {}\n255\n", map.grid.columns, map.grid.rows)?;
+    output.write_all(&map.levels())?;
+    Ok(())
+}
+
+// black through red and yellow to white
+fn heat(level: u8) -> [u8; 3] {
+    let t = level as u32 * 3;
+    [
+        t.min(255) as u8,
+        t.saturating_sub(255).min(255) as u8,
+        t.saturating_sub(510).min(255) as u8,
+    ]
+}
+
+/// Writes the map as a binary PPM with a black-red-yellow-white color scale.
+pub fn write_ppm<W: Write>(map: &Map, mut output: W) -> EGSResult<()> {
+    write!(output, "P6\n{} {}\n255\n", map.grid.columns, map.grid.rows)?;
+    let pixels: Vec<u8> = map.levels().into_iter().flat_map(heat).collect();
+    output.write_all(&pixels)?;
+    Ok(())
+}
+
+/// Reads a map over the window from whitespace separated rows of numbers, top row first,
+/// as written by `write_matrix`. Blank lines and `#` comments are skipped.
+pub fn read_matrix<R: Read>(
+    input: R,
+    x_min: f32,
+    x_max: f32,
+    y_min: f32,
+    y_max: f32,
+) -> EGSResult<Map> {
+    let mut values = Vec::new();
+    let mut columns = 0;
+    let mut rows = 0;
+    for (index, line) in BufReader::new(input).lines().enumerate() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let row: Result<Vec<f64>, _> = line.split_whitespace().map(|v| v.parse()).collect();
+        let row = row.map_err(|_| EGSError::BadMap(format!("line {}: invalid number", index + 1)))?;
+        if rows > 0 && row.len() != columns {
+            return Err(EGSError::BadMap(format!(
+                "line {}: {} columns but the first row has {}",
+                index + 1,
+                row.len(),
+                columns
+            )));
+        }
+        columns = row.len();
+        rows += 1;
+        values.extend(row);
+    }
+    Ok(Map {
+        grid: Grid::sized(x_min, x_max, y_min, y_max, columns, rows)?,
+        values,
+    })
+}
+
+/// Reads a map over the window from a PGM image, binary (P5) or plain (P2), as the
+/// fraction of the maximum gray level, so white is 1 and black is 0.
+pub fn read_pgm<R: Read>(
+    input: R,
+    x_min: f32,
+    x_max: f32,
+    y_min: f32,
+    y_max: f32,
+) -> EGSResult<Map> {
+    let mut bytes = Vec::new();
+    BufReader::new(input).read_to_end(&mut bytes)?;
+    // the magic number, width, height and maximum level, separated by whitespace and
+    // comments, then a single whitespace character before binary data
+    let mut position = 0;
+    let mut header = Vec::new();
+    while header.len() < 4 {
+        while position < bytes.len() && bytes[position].is_ascii_whitespace() {
+            position += 1;
+        }
+        if position < bytes.len() && bytes[position] == b'#' {
+            while position < bytes.len() && bytes[position] != b'\n' {
+                position += 1;
+            }
+            continue;
+        }
+        let start = position;
+        while position < bytes.len() && !bytes[position].is_ascii_whitespace() {
+            position += 1;
+        }
+        if start == position {
+            return Err(EGSError::BadMap("PGM header ended early".to_string()));
+        }
+        header.push(String::from_utf8_lossy(&bytes[start..position]).into_owned());
+    }
+    let binary = match header[0].as_str() {
+        "P5" => true,
+        "P2" => false,
+        magic => return Err(EGSError::BadMap(format!("{:?} is not a P2 or P5 PGM", magic))),
+    };
+    let number = |text: &str| -> EGSResult<usize> {
+        text.parse()
+            .map_err(|_| EGSError::BadMap(format!("invalid PGM header value {:?}", text)))
+    };
+    let columns = number(&header[1])?;
+    let rows = number(&header[2])?;
+    let max_level = number(&header[3])?;
+    if max_level == 0 || max_level > 65535 {
+        return Err(EGSError::BadMap(format!("PGM maximum level {} is out of range", max_level)));
+    }
+    // checked before the header's sizes are trusted
+    let grid = Grid::sized(x_min, x_max, y_min, y_max, columns, rows)?;
+    let pixels = columns * rows;
+    let levels: Vec<usize> = if binary {
+        let data = &bytes[(position + 1).min(bytes.len())..];
+        let size = if max_level < 256 { 1 } else { 2 };
+        if data.len() < pixels * size {
+            return Err(EGSError::BadMap(format!(
+                "PGM has {} bytes of data for {} pixels",
+                data.len(),
+                pixels
+            )));
+        }
+        if size == 1 {
+            data[..pixels].iter().map(|&b| b as usize).collect()
+        } else {
+            data[..pixels * 2]
+                .chunks(2)
+                .map(|pair| (pair[0] as usize) << 8 | pair[1] as usize)
+                .collect()
+        }
+    } else {
+        let text = String::from_utf8_lossy(&bytes[position..]);
+        let levels: EGSResult<Vec<usize>> = text.split_whitespace().map(number).collect();
+        levels?
+    };
+    if levels.len() < pixels {
+        return Err(EGSError::BadMap(format!(
+            "PGM has {} levels for {} pixels",
+            levels.len(),
+            pixels
+        )));
+    }
+    Ok(Map {
+        grid,
+        values: levels[..pixels]
+            .iter()
+            .map(|&level| level as f64 / max_level as f64)
+            .collect(),
+    })
+}
+
+/// Multiplies every particle's weight by the value of a map at its position, for example
+/// the transmission of a wedge, compensator or block. A particle uses the map of the first
+/// selection it matches and is unchanged if it matches none. With `distance`, particles
+/// are first projected that far along z (cm) along their direction. Particles that miss
+/// the map, or travel parallel to the plane, are scaled by `outside`. The total weight is
+/// not kept.
+pub fn reweight_map(
+    input_path: &Path,
+    output_path: &Path,
+    maps: &[(Selection, Map)],
+    distance: Option<f32>,
+    outside: f64,
+) -> EGSResult<()> {
+    for &(selection, ref map) in maps.iter() {
+        if let Some(value) = map.values.iter().find(|v| !v.is_finite() || **v < 0.0) {
+            return Err(EGSError::BadMap(format!(
+                "the {} map has value {}, which is negative or not finite",
+                selection, value
+            )));
+        }
+    }
+    if !outside.is_finite() || outside < 0.0 {
+        return Err(EGSError::BadMap(format!(
+            "outside value {} is negative or not finite",
+            outside
+        )));
+    }
+    scale_weights(input_path, output_path, &|record: &Record| {
+        let map = match maps.iter().find(|(selection, _)| selection.matches(record)) {
+            Some((_, map)) => map,
+            None => return 1.0,
+        };
+        let (x, y) = match distance {
+            Some(distance) => {
+                let w = if record.z_positive() {
+                    record.z_cos()
+                } else {
+                    -record.z_cos()
+                };
+                if w == 0.0 {
+                    return outside as f32;
+                }
+                (
+                    record.x_cm + distance * record.x_cos / w,
+                    record.y_cm + distance * record.y_cos / w,
+                )
+            }
+            None => (record.x_cm, record.y_cm),
+        };
+        map.value_at(x, y).unwrap_or(outside) as f32
+    })
+}
+
+fn particle_color(particle: Particle) -> &'static str {
+    match particle {
+        Particle::Photon => "#2ca02c",
+        Particle::Electron => "#1f77b4",
+        Particle::Positron => "#d62728",
+    }
+}
+
+/// Writes an SVG scatter plot of the positions of the first `count` selected particles
+/// inside the grid window, colored by particle type.
+pub fn write_scatter_svg<R: Read, W: Write>(
+    reader: PHSPReader<R>,
+    grid: &Grid,
+    selection: Selection,
+    count: usize,
+    mut output: W,
+) -> EGSResult<()> {
+    const SIZE: f32 = 600.0;
+    const MARGIN: f32 = 40.0;
+    let width = grid.x_max - grid.x_min;
+    let height = grid.y_max - grid.y_min;
+    let scale = SIZE / width.max(height);
+    let (plot_width, plot_height) = (width * scale, height * scale);
+    writeln!(
+        output,
+        "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{}\" height=\"{}\">",
+        plot_width + 2.0 * MARGIN,
+        plot_height + 2.0 * MARGIN
+    )?;
+    writeln!(
+        output,
+        "<rect x=\"{}\" y=\"{}\" width=\"{}\" height=\"{}\" fill=\"white\" stroke=\"black\"/>",
+        MARGIN, MARGIN, plot_width, plot_height
+    )?;
+    let mut plotted = 0;
+    for record in reader {
+        if plotted == count {
+            break;
+        }
+        let record = record?;
+        if !selection.matches(&record) || grid.pixel(record.x_cm, record.y_cm).is_none() {
+            continue;
+        }
+        writeln!(
+            output,
+            "<circle cx=\"{:.2}\" cy=\"{:.2}\" r=\"1\" fill=\"{}\"/>",
+            MARGIN + (record.x_cm - grid.x_min) * scale,
+            MARGIN + (grid.y_max - record.y_cm) * scale,
+            particle_color(record.particle())
+        )?;
+        plotted += 1;
+    }
+    let bottom = MARGIN + plot_height;
+    for (x, anchor, label) in [
+        (MARGIN, "start", grid.x_min),
+        (MARGIN + plot_width, "end", grid.x_max),
+    ] {
+        writeln!(
+            output,
+            "<text x=\"{}\" y=\"{}\" text-anchor=\"{}\" font-size=\"12\">{} cm</text>",
+            x,
+            bottom + 16.0,
+            anchor,
+            label
+        )?;
+    }
+    for (y, label) in [(bottom, grid.y_min), (MARGIN + 12.0, grid.y_max)] {
+        writeln!(
+            output,
+            "<text x=\"{}\" y=\"{}\" text-anchor=\"end\" font-size=\"12\">{} cm</text>",
+            MARGIN - 4.0,
+            y,
+            label
+        )?;
+    }
+    for (i, particle) in [Particle::Photon, Particle::Electron, Particle::Positron]
+        .iter()
+        .enumerate()
+    {
+        let x = MARGIN + i as f32 * 100.0;
+        writeln!(
+            output,
+            "<circle cx=\"{}\" cy=\"{}\" r=\"4\" fill=\"{}\"/>",
+            x + 4.0,
+            MARGIN / 2.0,
+            particle_color(*particle)
+        )?;
+        writeln!(
+            output,
+            "<text x=\"{}\" y=\"{}\" font-size=\"12\">{:?}</text>",
+            x + 12.0,
+            MARGIN / 2.0 + 4.0,
+            particle
+        )?;
+    }
+    writeln!(output, "</svg>")?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{particle, phsp, tmp_path};
+
+    #[test]
+    fn map_rows_run_from_the_top() {
+        let bytes = phsp(
+            &[
+                particle(0, 1.0, -0.5, 0.5, 1.0),
+                particle(0, 1.0, -0.5, 0.5, 1.0),
+                particle(1 << 30, 1.0, 0.5, -0.5, 1.0),
+                particle(0, 1.0, 3.0, 0.0, 1.0),
+            ],
+            2.0,
+        );
+        let grid = Grid::new(-1.0, 1.0, -1.0, 1.0, 1.0).unwrap();
+        assert_eq!((grid.columns, grid.rows), (2, 2));
+        assert!(Grid::new(0.0, f32::NAN, 0.0, 1.0, 0.1).is_err());
+        assert!(Grid::new(0.0, 1.0, 0.0, f32::INFINITY, 0.1).is_err());
+        assert!(Grid::new(-100.0, 100.0, -100.0, 100.0, 1e-7).is_err());
+        assert_eq!(grid.pixel(-0.5, 0.5), Some((0, 0)));
+        assert_eq!(grid.pixel(f32::NAN, 0.5), None);
+        assert_eq!(grid.pixel(-0.5, f32::NAN), None);
+        let reader = PHSPReader::new(&bytes[..], None).unwrap();
+        let map = fluence_map(reader, &grid, Selection::All, Quantity::Count).unwrap();
+        assert_eq!(map.values, vec![2.0, 0.0, 0.0, 1.0]);
+        let reader = PHSPReader::new(&bytes[..], None).unwrap();
+        let map = fluence_map(reader, &grid, Selection::Photons, Quantity::Fluence).unwrap();
+        assert_eq!(map.values, vec![1.0, 0.0, 0.0, 0.0]);
+
+        let mut pgm = Vec::new();
+        write_pgm(&map, &mut pgm).unwrap();
+        assert_eq!(&pgm[..], b"P5\n2 2\n255\n\xff\x00\x00\x00");
+        let mut ppm = Vec::new();
+        write_ppm(&map, &mut ppm).unwrap();
+        assert_eq!(&ppm[ppm.len() - 12..ppm.len() - 9], &[255, 255, 255]);
+    }
+
+    #[test]
+    fn reads_maps_from_text_and_pgm() {
+        let text = "# 2 columns x 2 rows\n1 0.5\n\n0.25 0\n";
+        let map = read_matrix(text.as_bytes(), -1.0, 1.0, -1.0, 1.0).unwrap();
+        assert_eq!((map.grid.columns, map.grid.rows), (2, 2));
+        assert_eq!(map.value_at(0.5, 0.5), Some(0.5));
+        assert_eq!(map.value_at(-0.5, -0.5), Some(0.25));
+        assert_eq!(map.value_at(2.0, 0.0), None);
+        assert!(read_matrix("1 2\n3\n".as_bytes(), -1.0, 1.0, -1.0, 1.0).is_err());
+
+        let binary = b"P5\n# comment\n2 1\n255\n\xff\x33";
+        let map = read_pgm(&binary[..], 0.0, 2.0, 0.0, 1.0).unwrap();
+        assert_eq!(map.values, vec![1.0, 0.2]);
+        let plain = "P2 2 1 4\n4 1\n";
+        let map = read_pgm(plain.as_bytes(), 0.0, 2.0, 0.0, 1.0).unwrap();
+        assert_eq!(map.values, vec![1.0, 0.25]);
+        assert!(read_pgm(&b"P5 2 2 255\n\x00"[..], 0.0, 2.0, 0.0, 1.0).is_err());
+        // sizes whose product overflows
+        let huge = format!("P5 {} {} 65535\n", usize::MAX / 2 + 1, 4);
+        match read_pgm(huge.as_bytes(), 0.0, 2.0, 0.0, 1.0) {
+            Err(EGSError::BadBinning(_)) => (),
+            other => panic!("expected BadBinning, got {:?}", other.map(|m| m.values.len())),
+        }
+    }
+
+    #[test]
+    fn reweight_map_projects_particles() {
+        let input = tmp_path("map_in.egsphsp1");
+        let output = tmp_path("map_out.egsphsp1");
+        // a photon at x = -0.5 heading 45 degrees toward +x, and an electron
+        let photon = Record {
+            x_cos: 0.5_f32.sqrt(),
+            ..particle(0, 1.0, -0.5, 0.5, -2.0)
+        };
+        let electron = particle(1 << 30, 1.0, -0.5, 0.5, -2.0);
+        std::fs::write(&input, phsp(&[photon, electron], 10.0)).unwrap();
+
+        // left half transmits 0.5, right half 0.1, for photons only
+        let map = read_matrix("0.5 0.1\n".as_bytes(), -1.0, 1.0, 0.0, 1.0).unwrap();
+        let maps = [(Selection::Photons, map)];
+        reweight_map(&input, &output, &maps, None, 1.0).unwrap();
+        let weights: Vec<f32> = PHSPReader::open(&output)
+            .unwrap()
+            .map(|r| r.unwrap().weight)
+            .collect();
+        assert_eq!(weights, vec![-1.0, -2.0]);
+
+        // moving 1 cm back along z takes the photon to x = 0.5
+        reweight_map(&input, &output, &maps, Some(-1.0), 1.0).unwrap();
+        let weights: Vec<f32> = PHSPReader::open(&output)
+            .unwrap()
+            .map(|r| r.unwrap().weight)
+            .collect();
+        let _ = std::fs::remove_file(&input);
+        let _ = std::fs::remove_file(&output);
+        assert!((weights[0] + 0.2).abs() < 1e-6);
+    }
+
+    #[test]
+    fn scatter_stops_after_count() {
+        let bytes = phsp(
+            &[
+                particle(0, 1.0, 0.0, 0.0, 1.0),
+                particle(0, 1.0, 5.0, 0.0, 1.0),
+                particle(1 << 30, 1.0, 0.5, 0.5, 1.0),
+                particle(0, 1.0, 0.1, 0.1, 1.0),
+            ],
+            2.0,
+        );
+        let grid = Grid::new(-1.0, 1.0, -1.0, 1.0, 0.1).unwrap();
+        let reader = PHSPReader::new(&bytes[..], None).unwrap();
+        let mut svg = Vec::new();
+        write_scatter_svg(reader, &grid, Selection::All, 2, &mut svg).unwrap();
+        let svg = String::from_utf8(svg).unwrap();
+        // two particles and the three legend markers
+        assert_eq!(svg.matches("<circle").count(), 5);
+        assert!(svg.contains("fill=\"#1f77b4\"/>"));
+    }
+}
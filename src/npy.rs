@@ -0,0 +1,351 @@
+use std::fs::{File, OpenOptions, create_dir_all};
+use std::io::prelude::*;
+use std::io::{BufWriter, SeekFrom};
+use std::path::Path;
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use crate::{BUFFER_CAPACITY, EGSError, EGSResult, PHSPReader, Record};
+
+// zip64 local file header (30 bytes plus name) with an extra field holding both sizes
+const LOCAL_HEADER_LENGTH: u64 = 30 + 20;
+const DOS_DATE_1980: u16 = (1 << 5) | 1;
+
+/// A column of the struct-of-arrays export: the numpy dtype and how to encode one value.
+struct Column {
+    name: &'static str,
+    descr: &'static str,
+    size: usize,
+    encode: fn(&Record, &mut [u8]),
+}
+
+fn signed_z_cos(record: &Record) -> f32 {
+    if record.z_positive() {
+        record.z_cos()
+    } else {
+        -record.z_cos()
+    }
+}
+
+const COLUMNS: [Column; 10] = [
+    Column {
+        name: "energy",
+        descr: "<f4",
+        size: 4,
+        encode: |r, b| LittleEndian::write_f32(b, r.total_energy()),
+    },
+    Column {
+        name: "x",
+        descr: "<f4",
+        size: 4,
+        encode: |r, b| LittleEndian::write_f32(b, r.x_cm),
+    },
+    Column {
+        name: "y",
+        descr: "<f4",
+        size: 4,
+        encode: |r, b| LittleEndian::write_f32(b, r.y_cm),
+    },
+    Column {
+        name: "x_cos",
+        descr: "<f4",
+        size: 4,
+        encode: |r, b| LittleEndian::write_f32(b, r.x_cos),
+    },
+    Column {
+        name: "y_cos",
+        descr: "<f4",
+        size: 4,
+        encode: |r, b| LittleEndian::write_f32(b, r.y_cos),
+    },
+    Column {
+        name: "z_cos",
+        descr: "<f4",
+        size: 4,
+        encode: |r, b| LittleEndian::write_f32(b, signed_z_cos(r)),
+    },
+    Column {
+        name: "weight",
+        descr: "<f4",
+        size: 4,
+        encode: |r, b| LittleEndian::write_f32(b, r.get_weight()),
+    },
+    Column {
+        name: "latch",
+        descr: "<u4",
+        size: 4,
+        encode: |r, b| LittleEndian::write_u32(b, r.latch),
+    },
+    Column {
+        name: "first_scored",
+        descr: "|b1",
+        size: 1,
+        encode: |r, b| b[0] = r.first_scored_by_primary_history() as u8,
+    },
+    Column {
+        name: "zlast",
+        descr: "<f4",
+        size: 4,
+        encode: |r, b| LittleEndian::write_f32(b, r.zlast.unwrap_or(crate::ZLAST_UNKNOWN)),
+    },
+];
+
+/// The `.npy` (version 1.0) header for a one dimensional array, padded so the data is
+/// 64 byte aligned.
+fn npy_header(descr: &str, length: u64) -> Vec<u8> {
+    let dict = format!(
+        "{{'descr': '{}', 'fortran_order': False, 'shape': ({},), }}",
+        descr, length
+    );
+    let unpadded = 10 + dict.len() + 1;
+    let padding = (64 - unpadded % 64) % 64;
+    let mut header = Vec::with_capacity(unpadded + padding);
+    header.extend_from_slice(b"\x93NUMPY\x01\x00");
+    header.extend_from_slice(&((dict.len() + padding + 1) as u16).to_le_bytes());
+    header.extend_from_slice(dict.as_bytes());
+    header.resize(header.len() + padding, b' ');
+    header.push(b'\n');
+    header
+}
+
+struct Crc32 {
+    table: [u32; 256],
+}
+
+impl Crc32 {
+    fn new() -> Crc32 {
+        let mut table = [0; 256];
+        for (i, entry) in table.iter_mut().enumerate() {
+            let mut c = i as u32;
+            for _ in 0..8 {
+                c = if c & 1 != 0 {
+                    0xedb8_8320 ^ (c >> 1)
+                } else {
+                    c >> 1
+                };
+            }
+            *entry = c;
+        }
+        Crc32 { table }
+    }
+    fn update(&self, crc: u32, bytes: &[u8]) -> u32 {
+        let mut c = !crc;
+        for &b in bytes {
+            c = self.table[((c ^ b as u32) & 0xff) as usize] ^ (c >> 8);
+        }
+        !c
+    }
+}
+
+struct ColumnWriter<'a> {
+    column: &'a Column,
+    writer: BufWriter<File>,
+    crc: u32,
+}
+
+impl ColumnWriter<'_> {
+    fn write(&mut self, bytes: &[u8], crc32: &Crc32) -> EGSResult<()> {
+        self.crc = crc32.update(self.crc, bytes);
+        self.writer.write_all(bytes)?;
+        Ok(())
+    }
+}
+
+fn columns(using_zlast: bool) -> Vec<&'static Column> {
+    COLUMNS
+        .iter()
+        .filter(|c| using_zlast || c.name != "zlast")
+        .collect()
+}
+
+fn write_columns(
+    reader: PHSPReader,
+    writers: &mut [ColumnWriter],
+    crc32: &Crc32,
+) -> EGSResult<()> {
+    let expected = reader.header.total_particles as u64;
+    let mut buffer = [0; 4];
+    let mut written = 0;
+    for record in reader {
+        let record = record?;
+        for writer in writers.iter_mut() {
+            let bytes = &mut buffer[..writer.column.size];
+            (writer.column.encode)(&record, bytes);
+            writer.write(bytes, crc32)?;
+        }
+        written += 1;
+    }
+    // the array lengths were fixed from the header before any record was read
+    if written != expected {
+        return Err(EGSError::BadLength);
+    }
+    for writer in writers.iter_mut() {
+        writer.writer.flush()?;
+    }
+    Ok(())
+}
+
+/// Writes one `.npy` file per field into the directory `output_path`.
+pub fn export_npy(input_path: &Path, output_path: &Path) -> EGSResult<()> {
+    let reader = PHSPReader::open(input_path)?;
+    let length = reader.header.total_particles as u64;
+    create_dir_all(output_path)?;
+    let crc32 = Crc32::new();
+    let mut writers = Vec::new();
+    for column in columns(reader.header.using_zlast) {
+        let file = File::create(output_path.join(format!("{}.npy", column.name)))?;
+        let mut writer = BufWriter::with_capacity(BUFFER_CAPACITY, file);
+        writer.write_all(&npy_header(column.descr, length))?;
+        writers.push(ColumnWriter {
+            column,
+            writer,
+            crc: 0,
+        });
+    }
+    write_columns(reader, &mut writers, &crc32)?;
+    println!(
+        "Wrote {} arrays of {} particles to {}",
+        writers.len(),
+        length,
+        output_path.display()
+    );
+    Ok(())
+}
+
+/// Writes all fields as arrays in a single (uncompressed, zip64) `.npz` archive. The
+/// size of every array is known from the header, so each column is streamed straight
+/// into its place in the archive in a single pass over the records.
+pub fn export_npz(input_path: &Path, output_path: &Path) -> EGSResult<()> {
+    let reader = PHSPReader::open(input_path)?;
+    let length = reader.header.total_particles as u64;
+    let columns = columns(reader.header.using_zlast);
+    let mut file = File::create(output_path)?;
+
+    struct Entry {
+        name: String,
+        offset: u64,
+        size: u64,
+    }
+    let crc32 = Crc32::new();
+    let mut entries = Vec::new();
+    let mut writers = Vec::new();
+    let mut offset = 0;
+    for column in columns {
+        let name = format!("{}.npy", column.name);
+        let header = npy_header(column.descr, length);
+        let size = header.len() as u64 + length * column.size as u64;
+        let data_offset = offset + LOCAL_HEADER_LENGTH + name.len() as u64;
+        let mut handle = OpenOptions::new().write(true).open(output_path)?;
+        handle.seek(SeekFrom::Start(data_offset))?;
+        let mut writer = ColumnWriter {
+            column,
+            writer: BufWriter::with_capacity(BUFFER_CAPACITY, handle),
+            crc: 0,
+        };
+        writer.write(&header, &crc32)?;
+        writers.push(writer);
+        entries.push(Entry { name, offset, size });
+        offset = data_offset + size;
+    }
+    write_columns(reader, &mut writers, &crc32)?;
+
+    let mut central = Vec::new();
+    for (entry, writer) in entries.iter().zip(writers.iter()) {
+        let mut local = vec![0; LOCAL_HEADER_LENGTH as usize];
+        LittleEndian::write_u32(&mut local[0..4], 0x0403_4b50);
+        LittleEndian::write_u16(&mut local[4..6], 45);
+        LittleEndian::write_u16(&mut local[12..14], DOS_DATE_1980);
+        LittleEndian::write_u32(&mut local[14..18], writer.crc);
+        LittleEndian::write_u32(&mut local[18..22], u32::MAX);
+        LittleEndian::write_u32(&mut local[22..26], u32::MAX);
+        LittleEndian::write_u16(&mut local[26..28], entry.name.len() as u16);
+        LittleEndian::write_u16(&mut local[28..30], 20);
+        LittleEndian::write_u16(&mut local[30..32], 1);
+        LittleEndian::write_u16(&mut local[32..34], 16);
+        LittleEndian::write_u64(&mut local[34..42], entry.size);
+        LittleEndian::write_u64(&mut local[42..50], entry.size);
+        local.splice(30..30, entry.name.bytes());
+        file.seek(SeekFrom::Start(entry.offset))?;
+        file.write_all(&local)?;
+
+        let mut record = vec![0; 46 + 28];
+        LittleEndian::write_u32(&mut record[0..4], 0x0201_4b50);
+        LittleEndian::write_u16(&mut record[4..6], 45);
+        LittleEndian::write_u16(&mut record[6..8], 45);
+        LittleEndian::write_u16(&mut record[14..16], DOS_DATE_1980);
+        LittleEndian::write_u32(&mut record[16..20], writer.crc);
+        LittleEndian::write_u32(&mut record[20..24], u32::MAX);
+        LittleEndian::write_u32(&mut record[24..28], u32::MAX);
+        LittleEndian::write_u16(&mut record[28..30], entry.name.len() as u16);
+        LittleEndian::write_u16(&mut record[30..32], 28);
+        LittleEndian::write_u32(&mut record[42..46], u32::MAX);
+        LittleEndian::write_u16(&mut record[46..48], 1);
+        LittleEndian::write_u16(&mut record[48..50], 24);
+        LittleEndian::write_u64(&mut record[50..58], entry.size);
+        LittleEndian::write_u64(&mut record[58..66], entry.size);
+        LittleEndian::write_u64(&mut record[66..74], entry.offset);
+        record.splice(46..46, entry.name.bytes());
+        central.extend_from_slice(&record);
+    }
+    let central_offset = offset;
+    let end_offset = central_offset + central.len() as u64;
+    let mut end = vec![0; 56 + 20 + 22];
+    // zip64 end of central directory record
+    LittleEndian::write_u32(&mut end[0..4], 0x0606_4b50);
+    LittleEndian::write_u64(&mut end[4..12], 44);
+    LittleEndian::write_u16(&mut end[12..14], 45);
+    LittleEndian::write_u16(&mut end[14..16], 45);
+    LittleEndian::write_u64(&mut end[24..32], entries.len() as u64);
+    LittleEndian::write_u64(&mut end[32..40], entries.len() as u64);
+    LittleEndian::write_u64(&mut end[40..48], central.len() as u64);
+    LittleEndian::write_u64(&mut end[48..56], central_offset);
+    // zip64 end of central directory locator
+    LittleEndian::write_u32(&mut end[56..60], 0x0706_4b50);
+    LittleEndian::write_u64(&mut end[64..72], end_offset);
+    LittleEndian::write_u32(&mut end[72..76], 1);
+    // end of central directory record
+    LittleEndian::write_u32(&mut end[76..80], 0x0605_4b50);
+    LittleEndian::write_u16(&mut end[84..86], entries.len() as u16);
+    LittleEndian::write_u16(&mut end[86..88], entries.len() as u16);
+    LittleEndian::write_u32(&mut end[88..92], u32::MAX);
+    LittleEndian::write_u32(&mut end[92..96], u32::MAX);
+    file.seek(SeekFrom::Start(central_offset))?;
+    file.write_all(&central)?;
+    file.write_all(&end)?;
+    println!(
+        "Wrote {} arrays of {} particles to {}",
+        entries.len(),
+        length,
+        output_path.display()
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn npy_header_is_aligned() {
+        for length in [0, 7, 123_456_789_012] {
+            let header = npy_header("<f4", length);
+            assert_eq!(header.len() % 64, 0);
+            assert_eq!(&header[..8], b"\x93NUMPY\x01\x00");
+            assert_eq!(
+                LittleEndian::read_u16(&header[8..10]) as usize,
+                header.len() - 10
+            );
+            assert_eq!(*header.last().unwrap(), b'\n');
+            let dict = String::from_utf8_lossy(&header[10..]);
+            assert!(dict.contains(&format!("'shape': ({},)", length)), "{}", dict);
+        }
+    }
+
+    #[test]
+    fn crc32_matches_reference() {
+        let crc32 = Crc32::new();
+        assert_eq!(crc32.update(0, b"123456789"), 0xcbf4_3926);
+        let split = crc32.update(crc32.update(0, b"1234"), b"56789");
+        assert_eq!(split, 0xcbf4_3926);
+    }
+}
@@ -0,0 +1,405 @@
+use std::io::prelude::*;
+
+use crate::analysis::Distribution;
+use crate::EGSResult;
+
+const COLORS: [&str; 10] = [
+    "#1f77b4", "#d62728", "#2ca02c", "#ff7f0e", "#9467bd", "#8c564b", "#e377c2", "#7f7f7f",
+    "#bcbd22", "#17becf",
+];
+const LEFT: f64 = 80.0;
+const RIGHT: f64 = 20.0;
+const TOP: f64 = 40.0;
+const BOTTOM: f64 = 50.0;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Style {
+    /// Straight lines between the bin centers.
+    Line,
+    /// A flat line across each bin, as a histogram.
+    Step,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct PlotOptions {
+    pub style: Style,
+    pub log_x: bool,
+    pub log_y: bool,
+    pub error_bars: bool,
+    pub width: f64,
+    pub height: f64,
+}
+
+impl Default for PlotOptions {
+    fn default() -> PlotOptions {
+        PlotOptions {
+            style: Style::Step,
+            log_x: false,
+            log_y: false,
+            error_bars: true,
+            width: 720.0,
+            height: 480.0,
+        }
+    }
+}
+
+// maps data to pixels along one axis
+#[derive(Debug, Copy, Clone)]
+struct Axis {
+    min: f64,
+    max: f64,
+    log: bool,
+    start: f64,
+    end: f64,
+}
+
+impl Axis {
+    fn new(min: f64, max: f64, log: bool, start: f64, end: f64) -> Axis {
+        let (min, max) = if max > min {
+            (min, max)
+        } else if log {
+            (min / 10.0, min * 10.0)
+        } else {
+            (min - 1.0, min + 1.0)
+        };
+        Axis {
+            min,
+            max,
+            log,
+            start,
+            end,
+        }
+    }
+
+    fn position(&self, value: f64) -> Option<f64> {
+        let fraction = if self.log {
+            if value <= 0.0 {
+                return None;
+            }
+            (value / self.min).ln() / (self.max / self.min).ln()
+        } else {
+            (value - self.min) / (self.max - self.min)
+        };
+        if fraction.is_finite() {
+            Some(self.start + (self.end - self.start) * fraction)
+        } else {
+            None
+        }
+    }
+
+    fn clamped(&self, value: f64) -> Option<f64> {
+        let low = self.start.min(self.end);
+        let high = self.start.max(self.end);
+        self.position(value).map(|p| p.clamp(low, high))
+    }
+
+    // powers of ten for log axes, otherwise steps of 1, 2 or 5 times a power of ten
+    fn ticks(&self) -> Vec<f64> {
+        if self.log {
+            let first = self.min.log10().ceil() as i32;
+            let last = self.max.log10().floor() as i32;
+            let mut ticks: Vec<f64> = (first..=last).map(|p| 10f64.powi(p)).collect();
+            if ticks.len() < 2 {
+                ticks = vec![self.min, self.max];
+            }
+            return ticks;
+        }
+        let rough = (self.max - self.min) / 5.0;
+        let magnitude = 10f64.powf(rough.log10().floor());
+        let step = [1.0, 2.0, 5.0, 10.0]
+            .iter()
+            .map(|m| m * magnitude)
+            .find(|step| *step >= rough)
+            .unwrap_or(10.0 * magnitude);
+        let mut tick = (self.min / step).ceil() * step;
+        let mut ticks = Vec::new();
+        while tick <= self.max + step * 1e-9 {
+            // avoid printing -0 or 1e-17 for zero
+            ticks.push(if tick.abs() < step * 1e-9 { 0.0 } else { tick });
+            tick += step;
+        }
+        ticks
+    }
+}
+
+fn label(value: f64) -> String {
+    let magnitude = value.abs();
+    if value == 0.0 {
+        "0".to_string()
+    } else if !(1e-3..1e4).contains(&magnitude) {
+        format!("{:.0e}", value)
+    } else {
+        let text = format!("{:.4}", value);
+        text.trim_end_matches('0').trim_end_matches('.').to_string()
+    }
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+// the y range covering every plottable value and its error bar
+fn y_limits(distribution: &Distribution, options: &PlotOptions) -> (f64, f64) {
+    let (mut low, mut high) = (f64::INFINITY, f64::NEG_INFINITY);
+    for series in distribution.series.iter() {
+        for (value, uncertainty) in series.values.iter().zip(series.uncertainties.iter()) {
+            let error = if options.error_bars && uncertainty.is_finite() {
+                *uncertainty
+            } else {
+                0.0
+            };
+            if !value.is_finite() || (options.log_y && *value <= 0.0) {
+                continue;
+            }
+            let bottom = value - error;
+            low = low.min(if options.log_y && bottom <= 0.0 { *value } else { bottom });
+            high = high.max(value + error);
+        }
+    }
+    if !low.is_finite() {
+        return if options.log_y { (0.1, 1.0) } else { (0.0, 1.0) };
+    }
+    if options.log_y {
+        (low / 1.5, high * 1.5)
+    } else {
+        let low = low.min(0.0);
+        (low, high + (high - low) * 0.05)
+    }
+}
+
+/// Draws every series of a distribution on one set of axes, with a legend, as a
+/// standalone SVG document.
+pub fn write_svg<W: Write>(
+    distribution: &Distribution,
+    options: &PlotOptions,
+    mut output: W,
+) -> EGSResult<()> {
+    let (width, height) = (options.width, options.height);
+    let edges = &distribution.edges;
+    let log_x = options.log_x && edges.first().is_some_and(|e| *e > 0.0);
+    let x = Axis::new(
+        edges.first().copied().unwrap_or(0.0),
+        edges.last().copied().unwrap_or(1.0),
+        log_x,
+        LEFT,
+        width - RIGHT,
+    );
+    let (y_min, y_max) = y_limits(distribution, options);
+    let y = Axis::new(y_min, y_max, options.log_y, height - BOTTOM, TOP);
+
+    writeln!(
+        output,
+        "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{}\" height=\"{}\" \
+         font-family=\"sans-serif\" font-size=\"12\">",
+        width, height
+    )?;
+    writeln!(
+        output,
+        "<rect width=\"{}\" height=\"{}\" fill=\"white\"/>",
+        width, height
+    )?;
+    writeln!(
+        output,
+        "<text x=\"{}\" y=\"{}\" text-anchor=\"middle\" font-size=\"16\">{}</text>",
+        width / 2.0,
+        TOP / 2.0 + 6.0,
+        escape(&distribution.title)
+    )?;
+
+    // grid lines and tick labels
+    for tick in x.ticks() {
+        if let Some(px) = x.position(tick) {
+            writeln!(
+                output,
+                "<line x1=\"{:.1}\" y1=\"{}\" x2=\"{:.1}\" y2=\"{}\" stroke=\"#dddddd\"/>",
+                px,
+                TOP,
+                px,
+                height - BOTTOM
+            )?;
+            writeln!(
+                output,
+                "<text x=\"{:.1}\" y=\"{}\" text-anchor=\"middle\">{}</text>",
+                px,
+                height - BOTTOM + 16.0,
+                label(tick)
+            )?;
+        }
+    }
+    for tick in y.ticks() {
+        if let Some(py) = y.position(tick) {
+            writeln!(
+                output,
+                "<line x1=\"{}\" y1=\"{:.1}\" x2=\"{}\" y2=\"{:.1}\" stroke=\"#dddddd\"/>",
+                LEFT,
+                py,
+                width - RIGHT,
+                py
+            )?;
+            writeln!(
+                output,
+                "<text x=\"{}\" y=\"{:.1}\" text-anchor=\"end\">{}</text>",
+                LEFT - 6.0,
+                py + 4.0,
+                label(tick)
+            )?;
+        }
+    }
+    writeln!(
+        output,
+        "<rect x=\"{}\" y=\"{}\" width=\"{}\" height=\"{}\" fill=\"none\" stroke=\"black\"/>",
+        LEFT,
+        TOP,
+        width - LEFT - RIGHT,
+        height - TOP - BOTTOM
+    )?;
+    writeln!(
+        output,
+        "<text x=\"{}\" y=\"{}\" text-anchor=\"middle\">{}</text>",
+        (LEFT + width - RIGHT) / 2.0,
+        height - 12.0,
+        escape(&distribution.x_label)
+    )?;
+    writeln!(
+        output,
+        "<text transform=\"translate(16,{}) rotate(-90)\" text-anchor=\"middle\">{}</text>",
+        (TOP + height - BOTTOM) / 2.0,
+        escape(&distribution.y_label)
+    )?;
+
+    let centers = distribution.centers();
+    for (i, series) in distribution.series.iter().enumerate() {
+        let color = COLORS[i % COLORS.len()];
+        // the path is broken wherever a value cannot be drawn (NaN, or not positive on a
+        // log axis)
+        let mut path = String::new();
+        let mut drawing = false;
+        for (j, value) in series.values.iter().enumerate() {
+            let py = match y.clamped(*value) {
+                Some(py) if value.is_finite() => py,
+                _ => {
+                    drawing = false;
+                    continue;
+                }
+            };
+            let points = match options.style {
+                Style::Line => vec![x.position(centers[j])],
+                Style::Step => vec![x.position(edges[j]), x.position(edges[j + 1])],
+            };
+            for px in points.into_iter().flatten() {
+                path.push_str(&format!(
+                    "{}{:.1},{:.1} ",
+                    if drawing { "L" } else { "M" },
+                    px,
+                    py
+                ));
+                drawing = true;
+            }
+        }
+        writeln!(
+            output,
+            "<path d=\"{}\" fill=\"none\" stroke=\"{}\" stroke-width=\"1.5\"/>",
+            path.trim_end(),
+            color
+        )?;
+        if options.error_bars {
+            for (j, (value, uncertainty)) in series
+                .values
+                .iter()
+                .zip(series.uncertainties.iter())
+                .enumerate()
+            {
+                if !value.is_finite() || !uncertainty.is_finite() || *uncertainty == 0.0 {
+                    continue;
+                }
+                let px = x.position(centers[j]);
+                let low = y.clamped(value - uncertainty).or(y.clamped(*value));
+                let high = y.clamped(value + uncertainty);
+                if let (Some(px), Some(low), Some(high)) = (px, low, high) {
+                    writeln!(
+                        output,
+                        "<line x1=\"{:.1}\" y1=\"{:.1}\" x2=\"{:.1}\" y2=\"{:.1}\" stroke=\"{}\"/>",
+                        px, low, px, high, color
+                    )?;
+                }
+            }
+        }
+        // legend in the top right corner
+        let legend_y = TOP + 16.0 + 16.0 * i as f64;
+        let legend_x = width - RIGHT - 200.0;
+        writeln!(
+            output,
+            "<line x1=\"{}\" y1=\"{}\" x2=\"{}\" y2=\"{}\" stroke=\"{}\" stroke-width=\"2\"/>",
+            legend_x,
+            legend_y - 4.0,
+            legend_x + 20.0,
+            legend_y - 4.0,
+            color
+        )?;
+        writeln!(
+            output,
+            "<text x=\"{}\" y=\"{}\">{}</text>",
+            legend_x + 26.0,
+            legend_y,
+            escape(&series.label)
+        )?;
+    }
+    writeln!(output, "</svg>")?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::analysis::Series;
+
+    fn distribution(values: Vec<f64>) -> Distribution {
+        let bins = values.len();
+        Distribution {
+            title: "Spectrum <test>".to_string(),
+            x_label: "energy (MeV)".to_string(),
+            y_label: "fluence".to_string(),
+            edges: (0..=bins).map(|i| 0.1 * 10f64.powi(i as i32)).collect(),
+            log_x: true,
+            series: vec![Series {
+                label: "photons".to_string(),
+                uncertainties: values.iter().map(|v| v / 10.0).collect(),
+                values,
+            }],
+        }
+    }
+
+    #[test]
+    fn ticks_are_round_numbers() {
+        let linear = Axis::new(0.0, 0.95, false, 0.0, 100.0);
+        assert_eq!(linear.ticks(), vec![0.0, 0.2, 0.4, 0.6000000000000001, 0.8]);
+        let log = Axis::new(0.05, 20.0, true, 0.0, 100.0);
+        assert_eq!(log.ticks(), vec![0.1, 1.0, 10.0]);
+        assert_eq!(log.position(0.0), None);
+        assert_eq!(label(0.6000000000000001), "0.6");
+        assert_eq!(label(0.00001), "1e-5");
+    }
+
+    #[test]
+    fn log_plot_breaks_path_at_zero() {
+        let options = PlotOptions {
+            log_x: true,
+            log_y: true,
+            ..PlotOptions::default()
+        };
+        let mut svg = Vec::new();
+        write_svg(&distribution(vec![1.0, 0.0, 2.0, 4.0]), &options, &mut svg).unwrap();
+        let svg = String::from_utf8(svg).unwrap();
+        assert!(svg.starts_with("<svg") && svg.trim_end().ends_with("</svg>"));
+        assert!(svg.contains("Spectrum &lt;test&gt;"));
+        let path = svg.split("<path d=\"").nth(1).unwrap();
+        let path = &path[..path.find('"').unwrap()];
+        // a step for the first bin, then a new line across the third and fourth
+        assert_eq!(path.matches('M').count(), 2);
+        assert_eq!(path.matches('L').count(), 4);
+        // three error bars, none for the empty bin
+        assert_eq!(svg.matches("<line").count() - svg.matches("#dddddd").count(), 4);
+    }
+}
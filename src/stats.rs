@@ -0,0 +1,208 @@
+use std::io::prelude::*;
+use std::path::Path;
+
+use serde::Serialize;
+
+use crate::{EGSResult, PHSPReader, Particle, Record};
+
+/// Figures for one particle type over a full scan of a phase space. Averages are weighted
+/// by the (absolute) particle weight and use kinetic energy, as the header does. Averages
+/// are NaN (`null` in JSON) when there are no particles of the type.
+#[derive(Debug, Copy, Clone, PartialEq, Serialize)]
+pub struct ParticleStats {
+    pub count: u64,
+    pub total_weight: f64,
+    pub mean_energy: f64,
+    /// Mean energy weighted by energy fluence, sum(w E^2) / sum(w E).
+    pub energy_weighted_mean_energy: f64,
+    pub min_x: f32,
+    pub max_x: f32,
+    pub min_y: f32,
+    pub max_y: f32,
+    pub rms_radius: f64,
+    pub mean_x_cos: f64,
+    pub mean_y_cos: f64,
+    /// Signed, so backward moving particles pull it below zero.
+    pub mean_z_cos: f64,
+    /// Kish effective sample size, (sum w)^2 / sum(w^2).
+    pub effective_particles: f64,
+    /// Fraction of the weight carried by particles moving in -z.
+    pub backward_fraction: f64,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Serialize)]
+pub struct Stats {
+    pub photons: ParticleStats,
+    pub electrons: ParticleStats,
+    pub positrons: ParticleStats,
+}
+
+#[derive(Debug, Copy, Clone)]
+struct Sums {
+    count: u64,
+    weight: f64,
+    weight_squared: f64,
+    energy: f64,
+    energy_squared: f64,
+    radius_squared: f64,
+    x_cos: f64,
+    y_cos: f64,
+    z_cos: f64,
+    backward: f64,
+    min_x: f32,
+    max_x: f32,
+    min_y: f32,
+    max_y: f32,
+}
+
+impl Default for Sums {
+    fn default() -> Sums {
+        Sums {
+            count: 0,
+            weight: 0.0,
+            weight_squared: 0.0,
+            energy: 0.0,
+            energy_squared: 0.0,
+            radius_squared: 0.0,
+            x_cos: 0.0,
+            y_cos: 0.0,
+            z_cos: 0.0,
+            backward: 0.0,
+            min_x: f32::NAN,
+            max_x: f32::NAN,
+            min_y: f32::NAN,
+            max_y: f32::NAN,
+        }
+    }
+}
+
+impl Sums {
+    fn add(&mut self, record: &Record) {
+        let w = record.get_weight() as f64;
+        let e = record.kinetic_energy() as f64;
+        let (x, y) = (record.x_cm as f64, record.y_cm as f64);
+        let z_cos = record.z_cos() as f64;
+        self.count += 1;
+        self.weight += w;
+        self.weight_squared += w * w;
+        self.energy += w * e;
+        self.energy_squared += w * e * e;
+        self.radius_squared += w * (x * x + y * y);
+        self.x_cos += w * record.x_cos as f64;
+        self.y_cos += w * record.y_cos as f64;
+        if record.z_positive() {
+            self.z_cos += w * z_cos;
+        } else {
+            self.z_cos -= w * z_cos;
+            self.backward += w;
+        }
+        // f32::min and max ignore the NaN starting values
+        self.min_x = self.min_x.min(record.x_cm);
+        self.max_x = self.max_x.max(record.x_cm);
+        self.min_y = self.min_y.min(record.y_cm);
+        self.max_y = self.max_y.max(record.y_cm);
+    }
+
+    fn finish(&self) -> ParticleStats {
+        let (count, weight) = (self.count, self.weight);
+        let mean = |sum: f64| if count > 0 { sum / weight } else { f64::NAN };
+        ParticleStats {
+            count,
+            total_weight: weight,
+            mean_energy: mean(self.energy),
+            energy_weighted_mean_energy: if count > 0 {
+                self.energy_squared / self.energy
+            } else {
+                f64::NAN
+            },
+            min_x: self.min_x,
+            max_x: self.max_x,
+            min_y: self.min_y,
+            max_y: self.max_y,
+            rms_radius: mean(self.radius_squared).sqrt(),
+            mean_x_cos: mean(self.x_cos),
+            mean_y_cos: mean(self.y_cos),
+            mean_z_cos: mean(self.z_cos),
+            effective_particles: if count > 0 {
+                weight * weight / self.weight_squared
+            } else {
+                0.0
+            },
+            backward_fraction: mean(self.backward),
+        }
+    }
+}
+
+impl Stats {
+    pub fn particle(&self, particle: Particle) -> &ParticleStats {
+        match particle {
+            Particle::Photon => &self.photons,
+            Particle::Electron => &self.electrons,
+            Particle::Positron => &self.positrons,
+        }
+    }
+}
+
+/// Reads every record and accumulates statistics for each particle type.
+pub fn scan_reader<R: Read>(reader: PHSPReader<R>) -> EGSResult<Stats> {
+    let mut sums = [Sums::default(); 3];
+    for record in reader {
+        let record = record?;
+        let i = match record.particle() {
+            Particle::Photon => 0,
+            Particle::Electron => 1,
+            Particle::Positron => 2,
+        };
+        sums[i].add(&record);
+    }
+    Ok(Stats {
+        photons: sums[0].finish(),
+        electrons: sums[1].finish(),
+        positrons: sums[2].finish(),
+    })
+}
+
+pub fn scan(path: &Path) -> EGSResult<Stats> {
+    scan_reader(PHSPReader::open(path)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{particle, phsp};
+
+    #[test]
+    fn separates_particle_types() {
+        let records: Vec<Record> = [
+            particle(0, 1.0, 3.0, 0.0, 1.0),
+            particle(0, 3.0, -4.0, 0.0, -3.0),
+            particle(1 << 30, 1.511, 0.0, 0.0, 2.0),
+        ]
+        .into_iter()
+        .map(|r| Record { x_cos: 0.6, ..r })
+        .collect();
+        let bytes = phsp(&records, 10.0);
+        let stats = scan_reader(PHSPReader::new(&bytes[..], None).unwrap()).unwrap();
+
+        let photons = stats.particle(Particle::Photon);
+        assert_eq!(photons.count, 2);
+        assert_eq!(photons.total_weight, 4.0);
+        assert_eq!(photons.mean_energy, 2.5);
+        assert_eq!(photons.energy_weighted_mean_energy, 28.0 / 10.0);
+        assert_eq!((photons.min_x, photons.max_x), (-4.0, 3.0));
+        assert_eq!(photons.rms_radius, (57.0_f64 / 4.0).sqrt());
+        assert!((photons.mean_z_cos - (0.8 - 2.4) / 4.0).abs() < 1e-6);
+        assert_eq!(photons.effective_particles, 16.0 / 10.0);
+        assert_eq!(photons.backward_fraction, 0.75);
+
+        let electrons = stats.particle(Particle::Electron);
+        assert_eq!(electrons.count, 1);
+        assert!((electrons.mean_energy - 1.0).abs() < 1e-6);
+        assert_eq!(electrons.backward_fraction, 0.0);
+
+        assert_eq!(stats.positrons.count, 0);
+        assert!(stats.positrons.mean_energy.is_nan());
+        let json = serde_json::to_string(&stats.positrons).unwrap();
+        assert!(json.contains("\"mean_energy\":null"), "{}", json);
+    }
+}
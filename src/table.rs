@@ -0,0 +1,349 @@
+use std::fs::{remove_file, File};
+use std::io::prelude::*;
+use std::io::{BufReader, BufWriter};
+use std::path::Path;
+
+use crate::{
+    BUFFER_CAPACITY, EGSError, EGSResult, PHSPReader, PHSPTrackingWriter, Particle, Record,
+};
+
+pub const COLUMNS: [&str; 12] = [
+    "latch",
+    "energy",
+    "x",
+    "y",
+    "u",
+    "v",
+    "w",
+    "weight",
+    "zlast",
+    "charge",
+    "region",
+    "first_scored",
+];
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum TableFormat {
+    Csv,
+    Tsv,
+}
+
+impl TableFormat {
+    fn delimiter(&self) -> char {
+        match *self {
+            TableFormat::Csv => ',',
+            TableFormat::Tsv => '\t',
+        }
+    }
+}
+
+fn charge(record: &Record) -> i32 {
+    match record.particle() {
+        Particle::Photon => 0,
+        Particle::Electron => -1,
+        Particle::Positron => 1,
+    }
+}
+
+/// Writes every record as a row of decoded values: total energy in MeV, position in cm,
+/// signed direction cosines, weight magnitude, and the charge and region of origin taken
+/// from the latch.
+pub fn export_table<W: Write>(input_path: &Path, output: W, format: TableFormat) -> EGSResult<()> {
+    let reader = PHSPReader::open(input_path)?;
+    let mut writer = BufWriter::with_capacity(BUFFER_CAPACITY, output);
+    let d = format.delimiter();
+    writeln!(writer, "{}", COLUMNS.join(&d.to_string()))?;
+    for record in reader {
+        let record = record?;
+        let w = if record.z_positive() {
+            record.z_cos()
+        } else {
+            -record.z_cos()
+        };
+        let zlast = record.zlast.map(|z| z.to_string()).unwrap_or_default();
+        writeln!(
+            writer,
+            "{}{d}{}{d}{}{d}{}{d}{}{d}{}{d}{}{d}{}{d}{}{d}{}{d}{}{d}{}",
+            record.latch,
+            record.total_energy(),
+            record.x_cm,
+            record.y_cm,
+            record.x_cos,
+            record.y_cos,
+            w,
+            record.get_weight(),
+            zlast,
+            charge(&record),
+            record.region_number(),
+            record.first_scored_by_primary_history() as u8,
+            d = d
+        )?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+fn bad_table(line: usize, message: String) -> EGSError {
+    EGSError::BadTable(format!("line {}: {}", line, message))
+}
+
+/// Builds a phase space from a table with a header row naming its columns (any of
+/// `COLUMNS`, in any order). `energy`, `x`, `y`, `u`, `v` and `weight` are required. The
+/// file is MODE2 when there is a `zlast` column with values, which must then be on every
+/// row, while an empty `zlast` column (as exported from MODE0) means MODE0. `charge`
+/// and `region`, when present, override the matching latch bits. The z direction is taken
+/// from the sign of `w`, or of `weight` if there is no `w` column. An invalid row is an
+/// error and leaves no output file.
+pub fn import_table(
+    input_path: &Path,
+    output_path: &Path,
+    format: TableFormat,
+    total_particles_in_source: Option<f32>,
+) -> EGSResult<()> {
+    let reader = BufReader::with_capacity(BUFFER_CAPACITY, File::open(input_path)?);
+    let mut lines = reader
+        .lines()
+        .enumerate()
+        .map(|(i, line)| (i + 1, line))
+        .filter(|(_, line)| !matches!(line, Ok(l) if l.trim().is_empty()));
+    let names: Vec<String> = match lines.next() {
+        Some((_, line)) => line?
+            .split(format.delimiter())
+            .map(|name| name.trim().trim_matches('"').to_string())
+            .collect(),
+        None => return Err(bad_table(1, "missing header row".to_string())),
+    };
+    let column = |name: &str| names.iter().position(|n| n == name);
+    let mut required = [0; 6];
+    for (i, name) in ["energy", "x", "y", "u", "v", "weight"].iter().enumerate() {
+        required[i] = column(name)
+            .ok_or_else(|| bad_table(1, format!("missing required column {}", name)))?;
+    }
+    let [energy, x, y, u, v, weight] = required;
+    let (latch, w, zlast) = (column("latch"), column("w"), column("zlast"));
+    let (charge, region, first_scored) =
+        (column("charge"), column("region"), column("first_scored"));
+
+    // an empty zlast column (as exported from MODE0) still means MODE0
+    let mut lines = lines.peekable();
+    let using_zlast = match (zlast, lines.peek()) {
+        (Some(i), Some((_, Ok(line)))) => line
+            .split(format.delimiter())
+            .nth(i)
+            .is_some_and(|field| !field.trim().is_empty()),
+        _ => false,
+    };
+    let mut writer = PHSPTrackingWriter::create(output_path, using_zlast, 0.0)?;
+    // a bad row stops the import, and the partial output is removed
+    let write_records = || -> EGSResult<()> {
+        for (number, line) in lines {
+            let line = line?;
+            let fields: Vec<&str> = line.split(format.delimiter()).map(|f| f.trim()).collect();
+            if fields.len() != names.len() {
+                return Err(bad_table(
+                    number,
+                    format!("expected {} fields, found {}", names.len(), fields.len()),
+                ));
+            }
+            let float = |i: usize| -> EGSResult<f32> {
+                fields[i].parse::<f32>().map_err(|_| {
+                    bad_table(
+                        number,
+                        format!("{} is not a number: {:?}", names[i], fields[i]),
+                    )
+                })
+            };
+            let integer = |i: usize| -> EGSResult<i64> {
+                fields[i].parse::<i64>().map_err(|_| {
+                    bad_table(
+                        number,
+                        format!("{} is not an integer: {:?}", names[i], fields[i]),
+                    )
+                })
+            };
+            let mut record_latch = match latch {
+                Some(i) => u32::try_from(integer(i)?).map_err(|_| {
+                    bad_table(number, format!("latch {} is out of range", fields[i]))
+                })?,
+                None => 0,
+            };
+            if let Some(i) = charge {
+                record_latch &= !(0b11 << 29);
+                record_latch |= match integer(i)? {
+                    0 => 0,
+                    -1 => 1 << 30,
+                    1 => 1 << 29,
+                    other => return Err(bad_table(number, format!("invalid charge {}", other))),
+                };
+            }
+            if let Some(i) = region {
+                let value = integer(i)?;
+                if !(0..32).contains(&value) {
+                    return Err(bad_table(
+                        number,
+                        format!("region {} is not in 0-31", value),
+                    ));
+                }
+                record_latch = (record_latch & !(0x1f << 24)) | ((value as u32) << 24);
+            }
+            let record_weight = float(weight)?;
+            let z_negative = match w {
+                Some(i) => float(i)?.is_sign_negative(),
+                None => record_weight.is_sign_negative(),
+            };
+            let mut total_energy = float(energy)?.abs();
+            if let Some(i) = first_scored
+                && integer(i)? != 0
+            {
+                total_energy = -total_energy;
+            }
+            let record = Record {
+                latch: record_latch,
+                total_energy,
+                x_cm: float(x)?,
+                y_cm: float(y)?,
+                x_cos: float(u)?,
+                y_cos: float(v)?,
+                weight: if z_negative {
+                    -record_weight.abs()
+                } else {
+                    record_weight.abs()
+                },
+                zlast: match zlast {
+                    // the mode is set by the first row, so the rest must agree with it
+                    Some(i) if fields[i].is_empty() == using_zlast => {
+                        return Err(bad_table(
+                            number,
+                            "zlast must be given on every row or on none".to_string(),
+                        ));
+                    }
+                    Some(i) if using_zlast => Some(float(i)?),
+                    _ => None,
+                },
+            };
+            writer.write(&record)?;
+        }
+        Ok(())
+    };
+    if let Err(err) = write_records() {
+        drop(writer);
+        let _ = remove_file(output_path);
+        return Err(err);
+    }
+    let total_particles = writer.stats.total_particles;
+    // with no source count given, each imported particle stands for one history
+    writer
+        .set_total_particles_in_source(total_particles_in_source.unwrap_or(total_particles as f32));
+    let header = writer.finish()?;
+    println!(
+        "Imported {} particles ({} photons) into {}",
+        header.total_particles,
+        header.total_photons,
+        output_path.display()
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::tmp_path;
+
+    #[test]
+    fn import_hand_crafted_beam() {
+        let input = tmp_path("beam.tsv");
+        let output = tmp_path("beam.egsphsp1");
+        std::fs::write(
+            &input,
+            "x\ty\tu\tv\tw\tenergy\tweight\tcharge\tregion\n\
+             0\t0\t0\t0\t1\t6.0\t1\t0\t3\n\
+             \n\
+             1.5\t-2\t0.1\t0.2\t-0.97\t2.511\t0.5\t-1\t0\n",
+        )
+        .unwrap();
+        import_table(&input, &output, TableFormat::Tsv, Some(1000.0)).unwrap();
+        let reader = PHSPReader::open(&output).unwrap();
+        let header = reader.header;
+        let records: Vec<Record> = reader.map(|r| r.unwrap()).collect();
+        let _ = std::fs::remove_file(&input);
+        let _ = std::fs::remove_file(&output);
+
+        assert_eq!(&header.mode, b"MODE0");
+        assert_eq!(header.total_particles, 2);
+        assert_eq!(header.total_photons, 1);
+        assert_eq!(header.total_particles_in_source, 1000.0);
+        assert_eq!(header.max_energy, 6.0);
+        assert_eq!(records[0].region_number(), 3);
+        assert_eq!(records[0].particle(), Particle::Photon);
+        assert!(records[0].z_positive());
+        assert_eq!(records[1].particle(), Particle::Electron);
+        assert!(!records[1].z_positive());
+        assert_eq!(records[1].get_weight(), 0.5);
+        assert_eq!(records[1].x_cm, 1.5);
+    }
+
+    #[test]
+    fn import_reports_line_of_bad_value() {
+        let input = tmp_path("bad.csv");
+        let output = tmp_path("bad.egsphsp1");
+        std::fs::write(
+            &input,
+            "energy,x,y,u,v,weight\n1,0,0,0,0,1\n1,0,zero,0,0,1\n",
+        )
+        .unwrap();
+        let result = import_table(&input, &output, TableFormat::Csv, None);
+        let _ = std::fs::remove_file(&input);
+        // the rows before the bad one aren't left behind as a valid file
+        assert!(!output.exists());
+        match result {
+            Err(EGSError::BadTable(message)) => {
+                assert!(message.starts_with("line 3:"), "{}", message)
+            }
+            other => panic!("expected BadTable, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn import_rejects_latch_out_of_range() {
+        let output = tmp_path("latch.egsphsp1");
+        for latch in ["-1", "4294967296"] {
+            let input = tmp_path("latch.csv");
+            let table = format!("latch,energy,x,y,u,v,weight
+0,1,0,0,0,0,1
+{},1,0,0,0,0,1
+", latch);
+            std::fs::write(&input, table).unwrap();
+            let result = import_table(&input, &output, TableFormat::Csv, None);
+            let _ = std::fs::remove_file(&input);
+            let _ = std::fs::remove_file(&output);
+            match result {
+                Err(EGSError::BadTable(message)) => {
+                    assert_eq!(message, format!("line 3: latch {} is out of range", latch))
+                }
+                other => panic!("expected BadTable, got {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn import_rejects_zlast_on_some_rows() {
+        let output = tmp_path("mixed.egsphsp1");
+        let cases = [
+            ("missing", "1,0,0,0,0,1,5\n1,0,0,0,0,1,\n"),
+            ("extra", "1,0,0,0,0,1,\n1,0,0,0,0,1,5\n"),
+        ];
+        for (label, rows) in cases {
+            let input = tmp_path(&format!("{}.csv", label));
+            std::fs::write(&input, format!("energy,x,y,u,v,weight,zlast\n{}", rows)).unwrap();
+            let result = import_table(&input, &output, TableFormat::Csv, None);
+            let _ = std::fs::remove_file(&input);
+            let _ = std::fs::remove_file(&output);
+            match result {
+                Err(EGSError::BadTable(message)) => {
+                    assert!(message.starts_with("line 3:"), "{}", message)
+                }
+                other => panic!("expected BadTable, got {:?}", other),
+            }
+        }
+    }
+}
@@ -0,0 +1,42 @@
+//! Fixtures shared by the unit tests.
+
+use std::io::Cursor;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::{PHSPTrackingWriter, Record};
+
+static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// A path in the temporary directory that no other test uses, ending in `label`.
+pub fn tmp_path(label: &str) -> PathBuf {
+    let n = COUNTER.fetch_add(1, Ordering::SeqCst);
+    let pid = std::process::id();
+    std::env::temp_dir().join(format!("beamdpr_test_{}_{}_{}", pid, n, label))
+}
+
+/// A particle at `(x, y)` moving along the z axis, backwards if `weight` is negative.
+pub fn particle(latch: u32, energy: f32, x: f32, y: f32, weight: f32) -> Record {
+    Record {
+        latch,
+        total_energy: energy,
+        x_cm: x,
+        y_cm: y,
+        x_cos: 0.0,
+        y_cos: 0.0,
+        weight,
+        zlast: None,
+    }
+}
+
+/// A MODE0 phase space of `records`, with the header computed from them.
+pub fn phsp(records: &[Record], total_particles_in_source: f32) -> Vec<u8> {
+    let mut buffer = Cursor::new(Vec::new());
+    let mut writer =
+        PHSPTrackingWriter::new(&mut buffer, false, total_particles_in_source).unwrap();
+    for r in records {
+        writer.write(r).unwrap();
+    }
+    writer.finish().unwrap();
+    buffer.into_inner()
+}
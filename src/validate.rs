@@ -0,0 +1,287 @@
+use std::fmt;
+use std::fs::File;
+use std::io::prelude::*;
+use std::path::Path;
+
+use crate::{EGSError, EGSResult, ELECTRON_REST_MASS, HeaderStats, PHSPReader, Record};
+
+// header energies are single precision and BEAMnrc computes them before rounding
+const ENERGY_TOLERANCE: f32 = 1e-5;
+const DIRECTION_TOLERANCE: f32 = 1e-5;
+
+/// A single inconsistency found in a phase space file, either in the header or in the
+/// record at the given (zero based) index.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Problem {
+    pub record: Option<u64>,
+    pub message: String,
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.record {
+            Some(index) => write!(f, "record {}: {}", index, self.message),
+            None => write!(f, "header: {}", self.message),
+        }
+    }
+}
+
+fn header_problem(message: String) -> Problem {
+    Problem {
+        record: None,
+        message,
+    }
+}
+
+fn check_record(
+    index: u64,
+    record: &Record,
+    max_energy: f32,
+    min_energy: f32,
+    problems: &mut Vec<Problem>,
+) {
+    let mut report = |message: String| {
+        problems.push(Problem {
+            record: Some(index),
+            message,
+        })
+    };
+    let values = [
+        ("energy", record.total_energy),
+        ("x", record.x_cm),
+        ("y", record.y_cm),
+        ("x direction cosine", record.x_cos),
+        ("y direction cosine", record.y_cos),
+        ("weight", record.weight),
+        // a NaN ZLAST marks an unknown value (see ZLAST_UNKNOWN)
+        ("zlast", record.zlast.filter(|z| !z.is_nan()).unwrap_or(0.0)),
+    ];
+    let mut finite = true;
+    for (name, value) in values.iter() {
+        if !value.is_finite() {
+            report(format!("{} is not finite ({})", name, value));
+            finite = false;
+        }
+    }
+    if !finite {
+        return;
+    }
+    let cos_squared = record.x_cos * record.x_cos + record.y_cos * record.y_cos;
+    if cos_squared > 1.0 + DIRECTION_TOLERANCE {
+        report(format!(
+            "direction cosines ({}, {}) have x\u{b2}+y\u{b2} = {} > 1",
+            record.x_cos, record.y_cos, cos_squared
+        ));
+    }
+    let bits = (record.latch >> 29) & 0b11;
+    if bits == 0b11 {
+        report(format!(
+            "latch {:#010x} sets both the electron and positron charge bits",
+            record.latch
+        ));
+    }
+    if record.charged() && record.total_energy() < ELECTRON_REST_MASS - ENERGY_TOLERANCE {
+        report(format!(
+            "charged particle with total energy {} MeV below the electron rest mass",
+            record.total_energy()
+        ));
+    }
+    let energy = record.kinetic_energy();
+    if energy > max_energy + ENERGY_TOLERANCE {
+        report(format!(
+            "kinetic energy {} MeV above header maximum {} MeV",
+            energy, max_energy
+        ));
+    }
+    // the header minimum is only a bound on charged particles (see HeaderStats)
+    if record.charged() && energy < min_energy - ENERGY_TOLERANCE {
+        report(format!(
+            "kinetic energy {} MeV below header minimum {} MeV",
+            energy, min_energy
+        ));
+    }
+}
+
+/// Checks the structure of a phase space and the physical sense of every record,
+/// returning every problem found. The byte length is only checked when known.
+pub fn validate_reader<R: Read>(inner: R, length: Option<u64>) -> EGSResult<Vec<Problem>> {
+    let reader = PHSPReader::new(inner, None)?;
+    let header = reader.header;
+    let mut problems = Vec::new();
+    if header.total_particles < 0 {
+        problems.push(header_problem(format!(
+            "total particles is negative ({})",
+            header.total_particles
+        )));
+    }
+    if let Some(actual_size) = length {
+        let expected_size = header.expected_size() as u64;
+        if actual_size != expected_size {
+            let records = actual_size.saturating_sub(header.record_size) / header.record_size;
+            let partial = actual_size.saturating_sub(header.record_size) % header.record_size;
+            problems.push(header_problem(format!(
+                "file is {} bytes but {} records need {} bytes ({} complete records{})",
+                actual_size,
+                header.total_particles,
+                expected_size,
+                records,
+                if partial > 0 {
+                    format!(" and {} trailing bytes", partial)
+                } else {
+                    String::new()
+                }
+            )));
+        }
+    }
+    if !header.total_particles_in_source.is_finite() || header.total_particles_in_source <= 0.0 {
+        problems.push(header_problem(format!(
+            "total particles in source is {}",
+            header.total_particles_in_source
+        )));
+    }
+
+    let mut stats = HeaderStats::default();
+    for (index, record) in reader.enumerate() {
+        let record = match record {
+            Ok(record) => record,
+            Err(EGSError::Io(err)) if err.kind() == std::io::ErrorKind::UnexpectedEof => {
+                problems.push(Problem {
+                    record: Some(index as u64),
+                    message: "file ends before this record is complete".to_string(),
+                });
+                break;
+            }
+            Err(err) => return Err(err),
+        };
+        check_record(
+            index as u64,
+            &record,
+            header.max_energy,
+            header.min_energy,
+            &mut problems,
+        );
+        stats.add(&record)?;
+    }
+
+    let mut scanned = header;
+    stats.apply(&mut scanned);
+    if scanned.total_particles != header.total_particles {
+        problems.push(header_problem(format!(
+            "total particles is {} but {} records were read",
+            header.total_particles, scanned.total_particles
+        )));
+    }
+    if scanned.total_photons != header.total_photons {
+        problems.push(header_problem(format!(
+            "total photons is {} but records contain {}",
+            header.total_photons, scanned.total_photons
+        )));
+    }
+    if stats.total_particles > 0 {
+        for (name, expected, found) in [
+            ("maximum", header.max_energy, scanned.max_energy),
+            ("minimum", header.min_energy, scanned.min_energy),
+        ] {
+            let difference = (expected - found).abs();
+            if difference.is_nan() || difference > ENERGY_TOLERANCE {
+                problems.push(header_problem(format!(
+                    "{} energy is {} MeV but records give {} MeV",
+                    name, expected, found
+                )));
+            }
+        }
+    }
+    Ok(problems)
+}
+
+pub fn validate(path: &Path) -> EGSResult<Vec<Problem>> {
+    let file = File::open(path)?;
+    let length = (file.metadata()?).len();
+    validate_reader(file, Some(length))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{particle, phsp};
+    use crate::Header;
+
+    fn problems_in(bytes: &[u8]) -> Vec<Problem> {
+        validate_reader(bytes, Some(bytes.len() as u64)).unwrap()
+    }
+
+    #[test]
+    fn consistent_file_has_no_problems() {
+        let bytes = phsp(
+            &[
+                particle(0, 0.2, 0.0, 0.0, 1.0),
+                particle(1 << 30, 1.0, 0.0, 0.0, 1.0),
+                particle(1 << 29, 0.7, 0.0, 0.0, 1.0),
+            ],
+            100.0,
+        );
+        assert_eq!(problems_in(&bytes), vec![]);
+    }
+
+    #[test]
+    fn reports_bad_records_by_index() {
+        let mut bad_direction = particle(0, 0.2, 0.0, 0.0, 1.0);
+        bad_direction.x_cos = 0.95;
+        bad_direction.y_cos = 0.4;
+        let mut not_finite = particle(0, 0.2, 0.0, 0.0, 1.0);
+        not_finite.y_cm = f32::NAN;
+        let records = [
+            particle(0, 0.2, 0.0, 0.0, 1.0),
+            bad_direction,
+            not_finite,
+            particle(0b11 << 29, 1.0, 0.0, 0.0, 1.0),
+            particle(1 << 30, 0.3, 0.0, 0.0, 1.0),
+        ];
+        let bytes = phsp(&records, 100.0);
+        let indices: Vec<Option<u64>> = problems_in(&bytes).iter().map(|p| p.record).collect();
+        assert_eq!(indices, vec![Some(1), Some(2), Some(3), Some(4)]);
+    }
+
+    #[test]
+    fn reports_header_disagreeing_with_records() {
+        let mut bytes = phsp(
+            &[
+                particle(0, 0.2, 0.0, 0.0, 1.0),
+                particle(1 << 30, 1.0, 0.0, 0.0, 1.0),
+            ],
+            100.0,
+        );
+        let mut header = Header::empty(false);
+        header.total_particles = 2;
+        header.total_photons = 2;
+        header.max_energy = 0.1;
+        header.min_energy = 0.5;
+        header.total_particles_in_source = 100.0;
+        bytes[..25].copy_from_slice(&header.encode()[..25]);
+        let problems = problems_in(&bytes);
+        // photons, max, min in the header; the electron is out of both bounds
+        assert_eq!(problems.iter().filter(|p| p.record.is_none()).count(), 3);
+        assert_eq!(problems.iter().filter(|p| p.record == Some(1)).count(), 2);
+    }
+
+    #[test]
+    fn reports_truncated_file() {
+        let bytes = phsp(
+            &[
+                particle(0, 0.2, 0.0, 0.0, 1.0),
+                particle(0, 0.3, 0.0, 0.0, 1.0),
+                particle(0, 0.4, 0.0, 0.0, 1.0),
+            ],
+            100.0,
+        );
+        let truncated = &bytes[..bytes.len() - 10];
+        let problems = problems_in(truncated);
+        assert!(problems[0].record.is_none(), "length problem first");
+        assert!(problems.iter().any(|p| p.record == Some(2)));
+        assert!(
+            problems
+                .iter()
+                .any(|p| p.message.contains("3 but 2 records were read"))
+        );
+    }
+}
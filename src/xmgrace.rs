@@ -0,0 +1,103 @@
+use std::io::prelude::*;
+
+use crate::analysis::Distribution;
+use crate::EGSResult;
+
+// quotes would end the string in a grace command
+fn escape(text: &str) -> String {
+    text.replace('"', "'")
+}
+
+// the y range covering every value and its error bar, from zero for positive data
+fn y_range(distribution: &Distribution) -> (f64, f64) {
+    let (mut low, mut high) = (0.0_f64, 0.0_f64);
+    for series in distribution.series.iter() {
+        for (value, uncertainty) in series.values.iter().zip(series.uncertainties.iter()) {
+            if value.is_finite() && uncertainty.is_finite() {
+                low = low.min(value - uncertainty);
+                high = high.max(value + uncertainty);
+            }
+        }
+    }
+    if high <= low {
+        high = low + 1.0;
+    }
+    (low, high + (high - low) * 0.05)
+}
+
+/// Writes a distribution as an xmgrace project: one graph with axis labels and a legend,
+/// and one `xydy` set with error bars per series, plotted at the bin centers.
+pub fn write_agr<W: Write>(distribution: &Distribution, mut output: W) -> EGSResult<()> {
+    let x_min = distribution.edges.first().copied().unwrap_or(0.0);
+    let x_max = distribution.edges.last().copied().unwrap_or(1.0);
+    let (y_min, y_max) = y_range(distribution);
+    writeln!(output, "# Grace project file")?;
+    writeln!(output, "@version 50125")?;
+    writeln!(output, "@g0 on")?;
+    writeln!(output, "@with g0")?;
+    writeln!(output, "@    world {}, {}, {}, {}", x_min, y_min, x_max, y_max)?;
+    writeln!(output, "@    title \"{}\"", escape(&distribution.title))?;
+    writeln!(output, "@    xaxis label \"{}\"", escape(&distribution.x_label))?;
+    writeln!(output, "@    yaxis label \"{}\"", escape(&distribution.y_label))?;
+    if distribution.log_x {
+        writeln!(output, "@    xaxes scale Logarithmic")?;
+    }
+    writeln!(output, "@    legend on")?;
+    for (i, series) in distribution.series.iter().enumerate() {
+        // grace colors 1 to 15, skipping white
+        let color = i % 15 + 1;
+        writeln!(output, "@    s{} legend \"{}\"", i, escape(&series.label))?;
+        writeln!(output, "@    s{} line color {}", i, color)?;
+        writeln!(output, "@    s{} errorbar on", i)?;
+        writeln!(output, "@    s{} errorbar color {}", i, color)?;
+    }
+    let centers = distribution.centers();
+    for (i, series) in distribution.series.iter().enumerate() {
+        writeln!(output, "@target G0.S{}", i)?;
+        writeln!(output, "@type xydy")?;
+        for (j, center) in centers.iter().enumerate() {
+            // empty bins of a mean have no value to plot
+            if !series.values[j].is_finite() {
+                continue;
+            }
+            writeln!(
+                output,
+                "{} {:e} {:e}",
+                center, series.values[j], series.uncertainties[j]
+            )?;
+        }
+        writeln!(output, "&")?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::analysis::Series;
+
+    #[test]
+    fn writes_a_set_per_series() {
+        let series = |label: &str| Series {
+            label: label.to_string(),
+            values: vec![1.0, 2.0],
+            uncertainties: vec![0.1, 0.2],
+        };
+        let distribution = Distribution {
+            title: "Fluence \"test\"".to_string(),
+            x_label: "radius (cm)".to_string(),
+            y_label: "fluence".to_string(),
+            edges: vec![0.0, 1.0, 2.0],
+            log_x: false,
+            series: vec![series("photons"), series("electrons")],
+        };
+        let mut agr = Vec::new();
+        write_agr(&distribution, &mut agr).unwrap();
+        let agr = String::from_utf8(agr).unwrap();
+        assert!(agr.contains("@    title \"Fluence 'test'\""));
+        assert!(agr.contains("@    s1 legend \"electrons\""));
+        assert!(agr.contains("@    s1 errorbar on"));
+        assert!(agr.contains("@    world 0, 0, 2, 2.31"));
+        assert!(agr.contains("@target G0.S1\n@type xydy\n0.5 1e0 1e-1\n1.5 2e0 2e-1\n&"));
+    }
+}
@@ -1,13 +1,22 @@
 use float_cmp::ApproxEqUlps;
 use std::f64::consts;
 use std::fs::copy;
+use std::fs::read_dir;
 use std::fs::remove_file;
 use std::fs::File;
 use std::io::prelude::*;
 use std::path::Path;
 
-use egsphsp::PHSPReader;
-use egsphsp::{combine, compare, sample_combine, transform, translate, Transform};
+use egsphsp::analysis::Selection;
+use egsphsp::filter::{filter, Filter};
+use egsphsp::iaea::{egsphsp_to_iaea, iaea_paths, iaea_to_egsphsp, IAEAReader};
+use egsphsp::npy::{export_npy, export_npz};
+use egsphsp::table::{export_table, import_table, TableFormat};
+use egsphsp::validate::validate;
+use egsphsp::{EGSError, PHSPReader};
+use egsphsp::{
+    combine, compare, repair, sample_combine, slice, transform, translate, Transform,
+};
 
 fn identical(path1: &Path, path2: &Path) -> bool {
     let mut file1 = File::open(path1).unwrap();
@@ -202,7 +211,8 @@ fn combine_samples() {
             "expected {} particles in source but found {}",
             expected_in_source, reader.header.total_particles_in_source
         
-    )
+    );
+    remove_file(output_path).unwrap();
 }
 
 #[test]
@@ -233,6 +243,31 @@ fn combine_delete_flag() {
     remove_file(output_path).unwrap();
 }
 
+#[test]
+fn filter_in_place() {
+    let input_path = Path::new("test_data/first.egsphsp1");
+    let path = Path::new("test_data/test_filter_in_place.egsphsp1");
+    copy(input_path, path).unwrap();
+    let electrons = Filter {
+        particles: vec![Selection::Electrons],
+        ..Filter::default()
+    };
+    filter(path, path, &electrons).unwrap();
+    let reader = PHSPReader::open(path).unwrap();
+    assert!(reader.header.total_particles > 0);
+    assert_eq!(reader.header.total_photons, 0);
+    for record in reader {
+        assert!(electrons.matches(&record.unwrap()));
+    }
+    // the temporary file was renamed over the output, and nothing else was touched
+    assert!(!read_dir("test_data").unwrap().any(|entry| {
+        let name = entry.unwrap().file_name();
+        name.to_string_lossy().starts_with(".test_filter_in_place")
+    }));
+    assert!(File::open("test_data/test_filter_in_place.filter").is_err());
+    remove_file(path).unwrap();
+}
+
 #[test]
 fn translate_operation() {
     let input_path = Path::new("test_data/first.egsphsp1");
@@ -300,3 +335,256 @@ fn reflect_operation() {
     compare(input_path, output_path).unwrap();
     remove_file(output_path).unwrap();
 }
+
+#[test]
+fn iaea_round_trip() {
+    let input_path = Path::new("test_data/first.egsphsp1");
+    let iaea_path = Path::new("test_data/test_round_trip.IAEAphsp");
+    let output_path = Path::new("test_data/test_round_trip.egsphsp1");
+    egsphsp_to_iaea(input_path, iaea_path, 50.0).unwrap();
+    let reader = IAEAReader::open(iaea_path).unwrap();
+    assert_eq!(reader.header.particles, 9345);
+    assert_eq!(reader.header.photons, 8190);
+    assert_eq!(reader.header.electrons + reader.header.positrons, 9345 - 8190);
+    assert_eq!(reader.header.constants.z, 50.0);
+    iaea_to_egsphsp(iaea_path, output_path).unwrap();
+    let ireader = PHSPReader::from(File::open(input_path).unwrap()).unwrap();
+    let oreader = PHSPReader::from(File::open(output_path).unwrap()).unwrap();
+    let (ih, oh) = (ireader.header, oreader.header);
+    assert_eq!(ih.mode, oh.mode);
+    assert_eq!(ih.total_particles, oh.total_particles);
+    assert_eq!(ih.total_photons, oh.total_photons);
+    assert_eq!(ih.total_particles_in_source, oh.total_particles_in_source);
+    // header energies are recomputed from the (rounded) record energies
+    assert!((ih.max_energy - oh.max_energy).abs() < 1e-6);
+    assert!((ih.min_energy - oh.min_energy).abs() < 1e-6);
+    for (irecord, orecord) in ireader.map(|r| r.unwrap()).zip(oreader.map(|r| r.unwrap())) {
+        assert!(irecord.similar_to(&orecord), "{:?} != {:?}", irecord, orecord);
+        assert_eq!(irecord.z_positive(), orecord.z_positive());
+        assert_eq!(
+            irecord.first_scored_by_primary_history(),
+            orecord.first_scored_by_primary_history()
+        );
+    }
+    let (header_path, phsp_path) = iaea_paths(iaea_path);
+    remove_file(header_path).unwrap();
+    remove_file(phsp_path).unwrap();
+    remove_file(output_path).unwrap();
+}
+
+#[test]
+fn slice_operation() {
+    let input_path = Path::new("test_data/first.egsphsp1");
+    let output_path = Path::new("test_data/test_slice.egsphsp1");
+    slice(input_path, output_path, 5000, 10).unwrap();
+    let mut ireader = PHSPReader::open(input_path).unwrap();
+    let oreader = PHSPReader::open(output_path).unwrap();
+    assert_eq!(oreader.header.total_particles, 10);
+    assert_eq!(
+        File::open(output_path).unwrap().metadata().unwrap().len(),
+        11 * 28
+    );
+    let expected: Vec<_> = ireader.range(5000..5010).unwrap().map(|r| r.unwrap()).collect();
+    let found: Vec<_> = oreader.map(|r| r.unwrap()).collect();
+    assert_eq!(expected.len(), found.len());
+    for (e, f) in expected.iter().zip(found.iter()) {
+        assert!(e.similar_to(f), "{:?} != {:?}", e, f);
+    }
+    remove_file(output_path).unwrap();
+}
+
+#[test]
+fn beamnrc_files_validate() {
+    for path in [
+        "test_data/first.egsphsp1",
+        "test_data/second.egsphsp1",
+        "test_data/combined.egsphsp1",
+    ] {
+        let problems = validate(Path::new(path)).unwrap();
+        assert!(problems.is_empty(), "{}: {:?}", path, problems);
+    }
+}
+
+#[test]
+fn repair_truncated_file_in_place() {
+    let path = Path::new("test_data/test_repair.egsphsp1");
+    let mut bytes = std::fs::read("test_data/first.egsphsp1").unwrap();
+    // a killed job: stale header counts and half of the last record written
+    bytes.truncate(bytes.len() - 14);
+    bytes[5..13].copy_from_slice(&[0; 8]);
+    std::fs::write(path, &bytes).unwrap();
+    assert!(!validate(path).unwrap().is_empty());
+
+    let header = repair(path, path, None).unwrap();
+    let problems = validate(path).unwrap();
+    let reader = PHSPReader::open(path).unwrap();
+    remove_file(path).unwrap();
+
+    assert!(problems.is_empty(), "{:?}", problems);
+    assert_eq!(header.total_particles, 9344);
+    assert_eq!(reader.header.total_particles, 9344);
+    assert_eq!(reader.header.total_particles_in_source, 10000.0);
+}
+
+#[test]
+fn repair_rejects_too_many_records() {
+    let path = Path::new("test_data/test_repair_too_many.egsphsp1");
+    copy("test_data/first.egsphsp1", path).unwrap();
+    // a sparse file one record past what the header can count
+    let records = i32::MAX as u64 + 1;
+    File::options().write(true).open(path).unwrap().set_len(28 + records * 28).unwrap();
+    let result = repair(path, path, None);
+    remove_file(path).unwrap();
+    match result {
+        Err(EGSError::TooManyParticles(count)) => assert_eq!(count, records),
+        other => panic!("expected TooManyParticles, got {:?}", other.map(|h| h.total_particles)),
+    }
+}
+
+#[test]
+fn repair_to_new_file_sets_source_particles() {
+    let input_path = Path::new("test_data/first.egsphsp1");
+    let output_path = Path::new("test_data/test_repair_copy.egsphsp1");
+    repair(input_path, output_path, Some(500.0)).unwrap();
+    let reader = PHSPReader::open(output_path).unwrap();
+    let problems = validate(output_path).unwrap();
+    remove_file(output_path).unwrap();
+    assert!(problems.is_empty(), "{:?}", problems);
+    assert_eq!(reader.header.total_particles, 9345);
+    assert_eq!(reader.header.total_photons, 8190);
+    assert_eq!(reader.header.total_particles_in_source, 500.0);
+}
+
+#[test]
+fn table_round_trip() {
+    let input_path = Path::new("test_data/first.egsphsp1");
+    let table_path = Path::new("test_data/test_table.csv");
+    let output_path = Path::new("test_data/test_table.egsphsp1");
+    export_table(input_path, File::create(table_path).unwrap(), TableFormat::Csv).unwrap();
+    import_table(table_path, output_path, TableFormat::Csv, Some(10000.0)).unwrap();
+    let ireader = PHSPReader::open(input_path).unwrap();
+    let oreader = PHSPReader::open(output_path).unwrap();
+    let problems = validate(output_path).unwrap();
+    remove_file(table_path).unwrap();
+    remove_file(output_path).unwrap();
+    assert!(problems.is_empty(), "{:?}", problems);
+    assert_eq!(ireader.header.total_particles, oreader.header.total_particles);
+    assert_eq!(ireader.header.total_photons, oreader.header.total_photons);
+    assert_eq!(oreader.header.total_particles_in_source, 10000.0);
+    for (irecord, orecord) in ireader.map(|r| r.unwrap()).zip(oreader.map(|r| r.unwrap())) {
+        // shortest round trip formatting reproduces every value exactly
+        assert_eq!(format!("{:?}", irecord), format!("{:?}", orecord));
+    }
+}
+
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &b in bytes {
+        crc ^= b as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { 0xedb8_8320 ^ (crc >> 1) } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+fn le16(bytes: &[u8], at: usize) -> usize {
+    u16::from_le_bytes(bytes[at..at + 2].try_into().unwrap()) as usize
+}
+
+fn le32(bytes: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap())
+}
+
+fn le64(bytes: &[u8], at: usize) -> usize {
+    u64::from_le_bytes(bytes[at..at + 8].try_into().unwrap()) as usize
+}
+
+#[test]
+fn npy_export_decodes_columns() {
+    let input_path = Path::new("test_data/first.egsphsp1");
+    let dir = Path::new("test_data/test_npy");
+    let archive = Path::new("test_data/test_npy.npz");
+    export_npy(input_path, dir).unwrap();
+    export_npz(input_path, archive).unwrap();
+    let names = [
+        "energy", "x", "y", "x_cos", "y_cos", "z_cos", "weight", "latch", "first_scored",
+    ];
+    let arrays: Vec<Vec<u8>> = names
+        .iter()
+        .map(|name| std::fs::read(dir.join(format!("{}.npy", name))).unwrap())
+        .collect();
+    let zip = std::fs::read(archive).unwrap();
+    std::fs::remove_dir_all(dir).unwrap();
+    remove_file(archive).unwrap();
+
+    assert!(!Path::new("test_data/test_npy/zlast.npy").exists());
+    let records: Vec<_> = PHSPReader::open(input_path)
+        .unwrap()
+        .map(|r| r.unwrap())
+        .collect();
+    let (energy, z_cos, first_scored) = (&arrays[0], &arrays[5], &arrays[8]);
+    let data_start = energy.len() - records.len() * 4;
+    assert_eq!(data_start % 64, 0);
+    for (i, record) in records.iter().enumerate() {
+        let at = |bytes: &[u8]| {
+            f32::from_le_bytes(bytes[data_start + 4 * i..data_start + 4 * i + 4].try_into().unwrap())
+        };
+        assert_eq!(at(energy), record.total_energy());
+        assert_eq!(at(z_cos).is_sign_positive(), record.z_positive());
+        let flag = first_scored[first_scored.len() - records.len() + i];
+        assert_eq!(flag == 1, record.first_scored_by_primary_history());
+    }
+    for array in arrays.iter() {
+        assert_eq!(&array[..8], b"\x93NUMPY\x01\x00");
+        let dict = String::from_utf8_lossy(&array[10..10 + le16(array, 8)]);
+        assert!(dict.contains(&format!("'shape': ({},)", records.len())), "{}", dict);
+    }
+
+    // the archive: end of central directory, then the zip64 locator and record before it
+    let end = zip.len() - 22;
+    assert_eq!(le32(&zip, end), 0x0605_4b50);
+    assert_eq!(le16(&zip, end + 10), names.len());
+    let locator = end - 20;
+    assert_eq!(le32(&zip, locator), 0x0706_4b50);
+    let zip64_end = le64(&zip, locator + 8);
+    assert_eq!(zip64_end, locator - 56);
+    assert_eq!(le32(&zip, zip64_end), 0x0606_4b50);
+    assert_eq!(le64(&zip, zip64_end + 32), names.len());
+    let central_size = le64(&zip, zip64_end + 40);
+    let mut central = le64(&zip, zip64_end + 48);
+    assert_eq!(central + central_size, zip64_end);
+
+    // each member is named after its field, holds exactly the standalone .npy file and
+    // agrees with its local header
+    let mut expected_offset = 0;
+    for (name, array) in names.iter().zip(arrays.iter()) {
+        assert_eq!(le32(&zip, central), 0x0201_4b50);
+        let crc = le32(&zip, central + 16);
+        let name_length = le16(&zip, central + 28);
+        let extra_length = le16(&zip, central + 30);
+        let member_name = &zip[central + 46..central + 46 + name_length];
+        assert_eq!(member_name, format!("{}.npy", name).as_bytes());
+        let extra = central + 46 + name_length;
+        assert_eq!(le16(&zip, extra), 1);
+        let size = le64(&zip, extra + 4);
+        assert_eq!(le64(&zip, extra + 12), size);
+        let offset = le64(&zip, extra + 20);
+        assert_eq!(offset, expected_offset);
+
+        assert_eq!(le32(&zip, offset), 0x0403_4b50);
+        assert_eq!(le32(&zip, offset + 14), crc);
+        assert_eq!(le16(&zip, offset + 26), name_length);
+        assert_eq!(&zip[offset + 30..offset + 30 + name_length], member_name);
+        let local_extra = offset + 30 + name_length;
+        assert_eq!(le64(&zip, local_extra + 4), size);
+        let data = local_extra + le16(&zip, offset + 28);
+        assert_eq!(&zip[data..data + size], array.as_slice());
+        assert_eq!(crc32(array), crc, "{}", name);
+
+        expected_offset = data + size;
+        central = extra + extra_length;
+    }
+    assert_eq!(central, zip64_end);
+    assert_eq!(expected_offset, le64(&zip, zip64_end + 48));
+}